@@ -0,0 +1,89 @@
+// Compares the ways the VM can run a program: decoding every instruction
+// from bytes, dispatching from the predecoded program, dispatching with
+// superinstructions on top, running compiled closures, and the JIT.
+// Compiling is part of what gets timed, so straight-line code gains nothing
+// from closures.
+// Run with `cargo bench --bench dispatch`.
+use iridium::assembler::assemble;
+use iridium::vm::{Backend, Outcome, Stdin, VM};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 20;
+
+// straight-line arithmetic, repeated to make a long program
+fn arithmetic() -> String {
+    let body = "add $0 $1 $2\nmul $2 $1 $3\nsub $3 $0 $4\ndiv $3 $1 $5\n";
+    format!("load $0 #7\nload $1 #3\n{}", body.repeat(20_000))
+}
+
+// a tight counting loop, with a load-add and a compare-jump pair to fuse
+fn counting_loop() -> String {
+    "load $0 #60000
+     load $1 #1
+     load $2 #0
+     load $3 #16
+     sub $0 $1 $0
+     load $5 #3
+     add $4 $5 $4
+     neq $0 $2
+     jeq $3"
+        .to_string()
+}
+
+type Configure = fn(&mut VM);
+
+fn bytes(vm: &mut VM) {
+    vm.set_predecoding(false);
+    vm.set_fusion(false);
+}
+
+fn predecoded(vm: &mut VM) {
+    vm.set_fusion(false);
+}
+
+fn fused(_: &mut VM) {}
+
+fn closures(vm: &mut VM) {
+    vm.set_backend(Backend::Closures);
+}
+
+fn jit(vm: &mut VM) {
+    vm.set_backend(Backend::Jit);
+}
+
+fn time(code: &[u8], configure: Configure) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut vm = VM::new();
+            configure(&mut vm);
+            vm.stdin(code.iter().copied());
+            let start = Instant::now();
+            assert_eq!(black_box(vm.run()), Outcome::Finished);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let modes: [(&str, Configure); 5] = [
+        ("decoding every step", bytes),
+        ("predecoded", predecoded),
+        ("fused", fused),
+        ("closures", closures),
+        ("jit", jit),
+    ];
+    for (name, source) in [("arithmetic", arithmetic()), ("loop", counting_loop())] {
+        let code = assemble(&source).unwrap().code;
+        let baseline = time(&code, bytes);
+        println!("{name}");
+        for (mode, configure) in modes {
+            let elapsed = time(&code, configure);
+            println!(
+                "  {mode:<20} {elapsed:>10.2?}  {:.2}x",
+                baseline.as_secs_f64() / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// Splits bytecode into basic blocks and links them up.
+use super::{instructions, is_conditional, is_jump, jump_table_targets, resolve_jumps};
+use crate::instructions::{Instruction, Opcode};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Edge {
+    /// on to the next instruction, by falling through or not taking a JEQ
+    Next(usize),
+    /// a jump whose target is known
+    Jump(usize),
+    /// a jump whose target is only known at run time
+    Dynamic,
+}
+
+/// Instructions from `start` up to, not including, `end` that always run
+/// one after the other.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// where control can go after the block. Empty when it halts, or when
+    /// it jumps to a negative address, which faults. A conditional jump's
+    /// own edge comes first
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cfg {
+    /// in address order; the first one is the entry
+    pub blocks: Vec<BasicBlock>,
+    /// where the program ends. Edges here leave the program
+    pub end: usize,
+    instructions: Vec<Instruction>,
+}
+
+fn ends_block(opcode: Opcode) -> bool {
+    is_jump(opcode) || matches!(opcode, Opcode::HLT | Opcode::ILGL)
+}
+
+impl Cfg {
+    /// Builds the graph of the program's complete instructions. Jumps are
+    /// resolved as far as `resolve_jumps` manages.
+    pub fn new(code: &[u8]) -> Cfg {
+        let instructions: Vec<Instruction> = instructions(code).map(|(_, i)| i).collect();
+        let end = instructions.len() * 4;
+        let targets = resolve_jumps(code);
+
+        let mut leaders = BTreeSet::from([0]);
+        for (slot, instruction) in instructions.iter().enumerate() {
+            if ends_block(instruction.opcode()) {
+                leaders.insert(slot * 4 + 4);
+            }
+            if instruction.opcode() == Opcode::JTABLE {
+                leaders.extend(jump_table_targets(slot * 4, *instruction));
+            }
+        }
+        leaders.extend(
+            targets
+                .values()
+                .filter_map(|&target| usize::try_from(target).ok())
+                .filter(|target| target % 4 == 0),
+        );
+        let leaders: Vec<usize> = leaders.into_iter().filter(|&pc| pc < end).collect();
+
+        let blocks = leaders
+            .iter()
+            .zip(leaders.iter().skip(1).chain([&end]))
+            .map(|(&start, &block_end)| {
+                let last = block_end - 4;
+                let instruction = instructions[last / 4];
+                let opcode = instruction.opcode();
+                let jump = match targets.get(&last) {
+                    Some(&target) => usize::try_from(target).ok().map(Edge::Jump),
+                    None => Some(Edge::Dynamic),
+                };
+                let edges = match opcode {
+                    Opcode::HLT | Opcode::ILGL => vec![],
+                    Opcode::JTABLE => jump_table_targets(last, instruction)
+                        .map(Edge::Jump)
+                        .collect(),
+                    opcode if is_conditional(opcode) => {
+                        jump.into_iter().chain([Edge::Next(block_end)]).collect()
+                    }
+                    opcode if is_jump(opcode) => jump.into_iter().collect(),
+                    _ => vec![Edge::Next(block_end)],
+                };
+                BasicBlock {
+                    start,
+                    end: block_end,
+                    edges,
+                }
+            })
+            .collect();
+        Cfg {
+            blocks,
+            end,
+            instructions,
+        }
+    }
+
+    /// The block starting at `pc`.
+    pub fn block(&self, pc: usize) -> Option<&BasicBlock> {
+        let i = self.blocks.binary_search_by_key(&pc, |b| b.start).ok()?;
+        Some(&self.blocks[i])
+    }
+
+    /// Blocks that can pass control to the block starting at `pc`.
+    pub fn predecessors(&self, pc: usize) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.iter().filter(move |block| {
+            block
+                .edges
+                .iter()
+                .any(|edge| matches!(edge, Edge::Next(t) | Edge::Jump(t) if *t == pc))
+        })
+    }
+
+    /// The graph in Graphviz's DOT language, with every block listing its
+    /// instructions.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=monospace];\n");
+        for block in &self.blocks {
+            let listing: String = (block.start..block.end)
+                .step_by(4)
+                .map(|pc| format!("{pc}: {}\\l", self.instructions[pc / 4]))
+                .collect();
+            writeln!(dot, "    b{} [label=\"{listing}\"];", block.start).unwrap();
+        }
+        let mut others = BTreeSet::new();
+        for block in &self.blocks {
+            let conditional = block.edges.len() > 1;
+            // the condition is the mnemonic without its leading j or b, or
+            // the whole of it for LOOP
+            let jump = self.instructions[block.end / 4 - 1].opcode();
+            let condition = match jump {
+                Opcode::LOOP => jump.mnemonic(),
+                jump => &jump.mnemonic()[1..],
+            };
+            let taken = format!(" [label=\"{condition}\"]");
+            for (i, edge) in block.edges.iter().enumerate() {
+                let (target, label) = match *edge {
+                    // a jump table's edges are its entries, then the default
+                    Edge::Jump(target) if jump == Opcode::JTABLE => {
+                        let entry = match i + 1 < block.edges.len() {
+                            true => i.to_string(),
+                            false => "default".to_string(),
+                        };
+                        (target, format!(" [label=\"{entry}\"]"))
+                    }
+                    Edge::Next(target) if conditional => (target, " [label=\"else\"]".to_string()),
+                    Edge::Jump(target) if conditional => (target, taken.clone()),
+                    Edge::Next(target) | Edge::Jump(target) => (target, String::new()),
+                    Edge::Dynamic => {
+                        others.insert("dynamic [shape=oval label=\"?\"]".to_string());
+                        writeln!(dot, "    b{} -> dynamic [style=dashed];", block.start).unwrap();
+                        continue;
+                    }
+                };
+                let node = if target >= self.end {
+                    others.insert("end [shape=oval]".to_string());
+                    "end".to_string()
+                } else if self.block(target).is_none() {
+                    // lands inside an instruction
+                    others.insert(format!("b{target} [shape=oval label=\"{target}\"]"));
+                    format!("b{target}")
+                } else {
+                    format!("b{target}")
+                };
+                writeln!(dot, "    b{} -> {node}{label};", block.start).unwrap();
+            }
+        }
+        for node in others {
+            writeln!(dot, "    {node};").unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn blocks(source: &str) -> Vec<(usize, usize, Vec<Edge>)> {
+        Cfg::new(&assemble(source).unwrap().code)
+            .blocks
+            .into_iter()
+            .map(|block| (block.start, block.end, block.edges))
+            .collect()
+    }
+
+    #[test]
+    fn test_blocks() {
+        // counts $0 down to 0
+        let source = "load $0 #3
+                      load $1 #1
+                      load $2 #0
+                      load $3 #12
+                      sub $0 $1 $0
+                      gt $0 $2
+                      jeq $3
+                      hlt
+                      jmp $4
+                      bneq #-4";
+        assert_eq!(
+            blocks(source),
+            [
+                (0, 12, vec![Edge::Next(12)]),
+                (12, 28, vec![Edge::Jump(12), Edge::Next(28)]),
+                (28, 32, vec![]),
+                (32, 36, vec![Edge::Dynamic]),
+                (36, 40, vec![Edge::Jump(32), Edge::Next(40)]),
+            ]
+        );
+        let cfg = Cfg::new(&assemble(source).unwrap().code);
+        let predecessors: Vec<usize> = cfg.predecessors(12).map(|b| b.start).collect();
+        assert_eq!(predecessors, [0, 12]);
+        assert_eq!(cfg.block(28).unwrap().end, 32);
+        assert!(cfg.block(16).is_none());
+    }
+
+    #[test]
+    fn test_jump_tables() {
+        let source = "jtable $0 #2
+                      .jumptable @a @b
+                      hlt
+                      a: load $1 #1
+                      b: hlt";
+        assert_eq!(
+            blocks(source),
+            [
+                (0, 4, vec![Edge::Jump(4), Edge::Jump(8), Edge::Jump(12)]),
+                (4, 8, vec![Edge::Jump(16)]),
+                (8, 12, vec![Edge::Jump(20)]),
+                (12, 16, vec![]),
+                (16, 20, vec![Edge::Next(20)]),
+                (20, 24, vec![]),
+            ]
+        );
+        let dot = Cfg::new(&assemble(source).unwrap().code).to_dot();
+        assert!(dot.contains("b0 -> b4 [label=\"0\"];\n    b0 -> b8 [label=\"1\"];"));
+        assert!(dot.contains("b0 -> b12 [label=\"default\"];"));
+    }
+
+    #[test]
+    fn test_loops() {
+        let source = "load $0 #4
+                      .loop $0
+                          inc $1
+                      .endloop
+                      hlt";
+        assert_eq!(
+            blocks(source),
+            [
+                (0, 4, vec![Edge::Next(4)]),
+                (4, 12, vec![Edge::Jump(4), Edge::Next(12)]),
+                (12, 16, vec![]),
+            ]
+        );
+        let dot = Cfg::new(&assemble(source).unwrap().code).to_dot();
+        assert!(dot.contains("b4 -> b4 [label=\"loop\"];\n    b4 -> b12 [label=\"else\"];"));
+    }
+
+    #[test]
+    fn test_dot() {
+        let code = assemble("load $0 #12\neq $0 $0\njeq $0\nload $1 #2\njmpf $1\njmp $2\nbneq #-4")
+            .unwrap()
+            .code;
+        assert_eq!(
+            Cfg::new(&code).to_dot(),
+            "digraph cfg {
+    node [shape=box fontname=monospace];
+    b0 [label=\"0: load $0 #12\\l4: eq $0 $0\\l8: jeq $0\\l\"];
+    b12 [label=\"12: load $1 #2\\l16: jmpf $1\\l\"];
+    b20 [label=\"20: jmp $2\\l\"];
+    b24 [label=\"24: bneq #-4\\l\"];
+    b0 -> b12 [label=\"eq\"];
+    b0 -> b12 [label=\"else\"];
+    b12 -> b20;
+    b20 -> dynamic [style=dashed];
+    b24 -> b20 [label=\"neq\"];
+    b24 -> end [label=\"else\"];
+    dynamic [shape=oval label=\"?\"];
+    end [shape=oval];
+}
+"
+        );
+    }
+}
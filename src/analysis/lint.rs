@@ -0,0 +1,363 @@
+// Dataflow lints over the control-flow graph. A jump whose target isn't
+// known could go anywhere, so it counts as an edge to every block.
+use super::cfg::{Cfg, Edge};
+use super::instructions;
+use super::verify::{verify, Problem};
+use crate::assembler::Program;
+use crate::instructions::{Instruction, Layout, Opcode};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Warning {
+    /// a register is read before anything writes to it, so it reads 0
+    UninitializedRead(u8),
+    /// a register is written and then always overwritten before it is read
+    DeadWrite(u8),
+    /// code nothing jumps or falls through to, up to `end`
+    Unreachable { end: usize },
+    /// something the verifier finds questionable but harmless, which for
+    /// now is only non-zero padding
+    Malformed(Problem),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UninitializedRead(r) => write!(f, "${r} is read before it is written"),
+            Warning::DeadWrite(r) => write!(f, "${r} is overwritten before it is read"),
+            Warning::Unreachable { .. } => write!(f, "unreachable code"),
+            Warning::Malformed(problem) => write!(f, "{problem}"),
+        }
+    }
+}
+
+/// A warning about the instruction at `pc`, which came from `line` of the
+/// source when that is known.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Lint {
+    pub pc: usize,
+    pub line: Option<usize>,
+    pub warning: Warning,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.warning),
+            None => write!(f, "{}: {}", self.pc, self.warning),
+        }
+    }
+}
+
+// registers as bits
+type Registers = u32;
+
+fn bit(register: u8) -> Registers {
+    1u32.checked_shl(register as u32).unwrap_or(0)
+}
+
+// the integer registers an instruction reads and the ones it writes. Float
+// registers aren't tracked
+fn uses_and_def(instruction: Instruction) -> (Registers, Registers) {
+    let opcode = instruction.opcode();
+    let operands = instruction.operands();
+    let register = |i: usize| {
+        if opcode.is_float_register(i) {
+            0
+        } else {
+            bit(operands[i])
+        }
+    };
+    let (a, b, c) = (register(0), register(1), register(2));
+    match opcode {
+        Opcode::INC | Opcode::DEC => return (a, a),
+        Opcode::REM => return (0, a),
+        Opcode::JTABLE => return (a, 0),
+        Opcode::MULW | Opcode::MULWU => return (a | b, c | bit(operands[2].wrapping_add(1))),
+        _ => (),
+    }
+    match opcode.layout() {
+        Layout::Nullary | Layout::Branch => (0, 0),
+        Layout::RegisterInteger => (0, a),
+        Layout::Unary | Layout::ComparisonImmediate => (a, 0),
+        Layout::RegisterBranch => (a, a),
+        Layout::Comparison => (a | b, 0),
+        Layout::Binary => (a | b, c),
+        Layout::UnaryResult | Layout::BinaryImmediate => (a, b),
+    }
+}
+
+struct Dataflow<'a> {
+    cfg: &'a Cfg,
+    instructions: Vec<Instruction>,
+}
+
+impl Dataflow<'_> {
+    // indices of the blocks control can go to after block `i`, and whether
+    // it can leave the program (or stop it) instead
+    fn successors(&self, i: usize) -> (Vec<usize>, bool) {
+        let block = &self.cfg.blocks[i];
+        let mut successors = vec![];
+        let mut exits = block.edges.is_empty();
+        for edge in &block.edges {
+            match *edge {
+                Edge::Next(target) | Edge::Jump(target) => {
+                    match self
+                        .cfg
+                        .blocks
+                        .binary_search_by_key(&target, |b| b.start)
+                        .ok()
+                    {
+                        Some(j) => successors.push(j),
+                        None => exits = true,
+                    }
+                }
+                Edge::Dynamic => successors.extend(0..self.cfg.blocks.len()),
+            }
+        }
+        (successors, exits)
+    }
+
+    fn block_instructions(
+        &self,
+        i: usize,
+    ) -> impl DoubleEndedIterator<Item = (usize, Instruction)> + '_ {
+        let block = &self.cfg.blocks[i];
+        (block.start..block.end)
+            .step_by(4)
+            .map(|pc| (pc, self.instructions[pc / 4]))
+    }
+
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.cfg.blocks.len()];
+        let mut pending = vec![0];
+        while let Some(i) = pending.pop() {
+            if i < reachable.len() && !reachable[i] {
+                reachable[i] = true;
+                pending.extend(self.successors(i).0);
+            }
+        }
+        reachable
+    }
+
+    // registers that may have been written on some path into each block
+    fn written(&self) -> Vec<Registers> {
+        let blocks = self.cfg.blocks.len();
+        let defs: Vec<Registers> = (0..blocks)
+            .map(|i| {
+                self.block_instructions(i)
+                    .fold(0, |d, (_, ins)| d | uses_and_def(ins).1)
+            })
+            .collect();
+        let mut written_in = vec![0; blocks];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..blocks {
+                let out = written_in[i] | defs[i];
+                for j in self.successors(i).0 {
+                    if written_in[j] | out != written_in[j] {
+                        written_in[j] |= out;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        written_in
+    }
+
+    // registers that may be read later, on leaving each block. Whatever is
+    // in the registers when the program stops is its result, so they all
+    // count as read then
+    fn live(&self) -> Vec<Registers> {
+        let blocks = self.cfg.blocks.len();
+        let transfer = |i: usize, out: Registers| {
+            self.block_instructions(i)
+                .rev()
+                .fold(out, |live, (_, instruction)| {
+                    let (uses, def) = uses_and_def(instruction);
+                    (live & !def) | uses
+                })
+        };
+        let mut live_out = vec![0; blocks];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..blocks).rev() {
+                let (successors, exits) = self.successors(i);
+                let out = successors
+                    .into_iter()
+                    .fold(if exits { Registers::MAX } else { 0 }, |out, j| {
+                        out | transfer(j, live_out[j])
+                    });
+                if out != live_out[i] {
+                    live_out[i] = out;
+                    changed = true;
+                }
+            }
+        }
+        live_out
+    }
+}
+
+/// Warnings about `program`, in address order. Only reachable code is
+/// checked for register reads and writes.
+pub fn lint(program: &Program) -> Vec<Lint> {
+    let cfg = Cfg::new(&program.code);
+    let dataflow = Dataflow {
+        cfg: &cfg,
+        instructions: instructions(&program.code).map(|(_, i)| i).collect(),
+    };
+    let mut warnings = vec![];
+    let reachable = dataflow.reachable();
+    let written = dataflow.written();
+    let live = dataflow.live();
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        if !reachable[i] {
+            // one warning for a whole run of unreachable blocks
+            if i == 0 || reachable[i - 1] {
+                let end = (i..cfg.blocks.len())
+                    .take_while(|&j| !reachable[j])
+                    .last()
+                    .map_or(block.end, |j| cfg.blocks[j].end);
+                warnings.push((block.start, Warning::Unreachable { end }));
+            }
+            continue;
+        }
+        let mut written = written[i];
+        for (pc, instruction) in dataflow.block_instructions(i) {
+            let (uses, def) = uses_and_def(instruction);
+            for r in (0..32).filter(|&r| uses & !written & bit(r) != 0) {
+                warnings.push((pc, Warning::UninitializedRead(r)));
+            }
+            written |= def;
+        }
+        let mut live = live[i];
+        let mut dead = vec![];
+        for (pc, instruction) in dataflow.block_instructions(i).rev() {
+            let (uses, def) = uses_and_def(instruction);
+            // a wide multiply is fine as long as one of its halves gets used
+            if def != 0 && def & live == 0 {
+                dead.push((pc, Warning::DeadWrite(def.trailing_zeros() as u8)));
+            }
+            live = (live & !def) | uses;
+        }
+        warnings.extend(dead.into_iter().rev());
+    }
+    warnings.extend(verify(&program.code).into_iter().filter_map(
+        |finding| match finding.problem {
+            Problem::NonZeroPadding { .. } => {
+                Some((finding.pc, Warning::Malformed(finding.problem)))
+            }
+            _ => None,
+        },
+    ));
+    warnings.sort_by_key(|&(pc, _)| pc);
+    warnings
+        .into_iter()
+        .map(|(pc, warning)| Lint {
+            pc,
+            line: program.line(pc),
+            warning,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn warnings(source: &str) -> Vec<(Option<usize>, Warning)> {
+        lint(&assemble(source).unwrap())
+            .into_iter()
+            .map(|lint| (lint.line, lint.warning))
+            .collect()
+    }
+
+    #[test]
+    fn test_registers() {
+        let source = "load $0 #1
+                      load $1 #2
+                      load $1 #3
+                      add $0 $1 $2
+                      mul $2 $5 $3";
+        assert_eq!(
+            warnings(source),
+            [
+                (Some(2), Warning::DeadWrite(1)),
+                (Some(5), Warning::UninitializedRead(5)),
+            ]
+        );
+        // only the high word of the product is used
+        let source = "load $0 #7
+                      mulwu $0 $0 $1
+                      add $2 $0 $3
+                      load $1 #0
+                      load $2 #0";
+        assert_eq!(warnings(source), []);
+        // $f0 isn't $0
+        let source = "loadf $f0 #1.5
+                      ftoi $f0 $0
+                      itof $0 $f1
+                      itof $1 $f2";
+        assert_eq!(warnings(source), [(Some(4), Warning::UninitializedRead(1))]);
+    }
+
+    #[test]
+    fn test_loops() {
+        // $4 is only written on an earlier trip around the loop, which is
+        // enough, but nothing ever writes $2
+        let source = "load $0 #3
+                      load $1 #1
+                      load $3 #12
+                      sub $0 $1 $0
+                      add $4 $1 $4
+                      gt $0 $2
+                      jeq $3";
+        assert_eq!(warnings(source), [(Some(6), Warning::UninitializedRead(2))]);
+        // a dynamic jump could go anywhere, so nothing is unreachable
+        assert_eq!(
+            warnings("load $0 #1\njmp $1\nhlt\nadd $0 $2 $3"),
+            [
+                (Some(2), Warning::UninitializedRead(1)),
+                (Some(4), Warning::UninitializedRead(2)),
+            ]
+        );
+        // every entry of a jump table is reachable
+        assert_eq!(
+            warnings("jtable $3 #1\n.jumptable @a\nhlt\na: hlt"),
+            [(Some(1), Warning::UninitializedRead(3))]
+        );
+        // a LOOP reads its counter before writing it back, so only the
+        // first load is dead
+        assert_eq!(
+            warnings("load $0 #3\n.loop $0\nload $0 #2\n.endloop"),
+            [(Some(1), Warning::DeadWrite(0))]
+        );
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let source = "load $0 #16
+                      jmp $0
+                      load $1 #2
+                      hlt
+                      load $2 #3
+                      hlt
+                      load $3 #4";
+        assert_eq!(
+            warnings(source),
+            [
+                (Some(3), Warning::Unreachable { end: 16 }),
+                (Some(7), Warning::Unreachable { end: 28 }),
+            ]
+        );
+        let lints = lint(&Program {
+            code: vec![9, 0, 0, 3],
+            ..Program::default()
+        });
+        assert_eq!(lints[0].to_string(), "0: $0 is read before it is written");
+        assert_eq!(lints[1].to_string(), "0: padding byte 3 is 3, should be 0");
+    }
+}
@@ -0,0 +1,184 @@
+//! Static analyses of bytecode, worked out without running it.
+pub mod cfg;
+pub mod lint;
+pub mod verify;
+
+use crate::instructions::{Instruction, Layout, Opcode};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The program's complete instructions and their addresses.
+pub(crate) fn instructions(code: &[u8]) -> impl Iterator<Item = (usize, Instruction)> + '_ {
+    code.chunks_exact(4)
+        .enumerate()
+        .map(|(slot, bytes)| (slot * 4, Instruction::decode(bytes)))
+}
+
+pub fn is_jump(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ | Opcode::JNEQ | Opcode::JTABLE
+    ) || is_branch(opcode)
+}
+
+/// Whether the opcode either jumps or goes on to the next instruction,
+/// depending on the state of the VM.
+pub fn is_conditional(opcode: Opcode) -> bool {
+    match opcode {
+        Opcode::JEQ | Opcode::JNEQ | Opcode::LOOP => true,
+        Opcode::BRA => false,
+        opcode => opcode.layout() == Layout::Branch,
+    }
+}
+
+// whether the opcode jumps by an offset from its own address
+fn is_branch(opcode: Opcode) -> bool {
+    matches!(opcode.layout(), Layout::Branch | Layout::RegisterBranch)
+}
+
+/// Everywhere the JTABLE `instruction` at `pc` can go: each entry of its
+/// table in order, then just past the table, where any other index lands.
+pub fn jump_table_targets(pc: usize, instruction: Instruction) -> impl Iterator<Item = usize> {
+    let [_, high, low] = instruction.operands();
+    let count = u16::from_be_bytes([high, low]) as usize;
+    (0..=count).map(move |entry| pc + 4 + 4 * entry)
+}
+
+/// Where the jump at `pc` goes when its register holds `value`, or, for a
+/// relative branch, when `value` is its offset.
+pub fn jump_target(pc: usize, opcode: Opcode, value: i32) -> Option<i64> {
+    match opcode {
+        Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => Some(value as i64),
+        opcode if is_branch(opcode) => Some(pc as i64 + value as i64),
+        Opcode::JMPF => Some(pc as i64 + 2 + value as i64),
+        Opcode::JMPB => Some(pc as i64 + 2 - value as i64),
+        _ => None,
+    }
+}
+
+/// Targets of the relative branches, and of the jumps whose register holds a
+/// known value, by the jump's address. Values are known from LOADs and arithmetic on known values
+/// earlier in the same straight run of code; anything after a jump, or at
+/// the target of one, might have been reached from elsewhere.
+pub fn resolve_jumps(code: &[u8]) -> BTreeMap<usize, i64> {
+    let mut entries: BTreeSet<usize> = instructions(code)
+        .filter(|(_, instruction)| instruction.opcode() == Opcode::JTABLE)
+        .flat_map(|(pc, instruction)| jump_table_targets(pc, instruction))
+        .collect();
+    loop {
+        let targets = resolve_jumps_once(code, &entries);
+        let before = entries.len();
+        entries.extend(targets.values().filter_map(|&t| usize::try_from(t).ok()));
+        if entries.len() == before {
+            return targets;
+        }
+    }
+}
+
+fn resolve_jumps_once(code: &[u8], entries: &BTreeSet<usize>) -> BTreeMap<usize, i64> {
+    let mut targets = BTreeMap::new();
+    let mut known: [Option<i32>; 32] = [None; 32];
+    for (pc, instruction) in instructions(code) {
+        if entries.contains(&pc) {
+            known = [None; 32];
+        }
+        let [a, b, c] = instruction.operands();
+        let value = |r: u8| known.get(r as usize).copied().flatten();
+        let result = match instruction.opcode() {
+            Opcode::LOAD => Some((a, Some(u16::from_be_bytes([b, c]) as i32))),
+            Opcode::ADD => Some((c, value(a).zip(value(b)).map(|(x, y)| x.wrapping_add(y)))),
+            Opcode::SUB => Some((c, value(a).zip(value(b)).map(|(x, y)| x.wrapping_sub(y)))),
+            Opcode::MUL => Some((c, value(a).zip(value(b)).map(|(x, y)| x.wrapping_mul(y)))),
+            Opcode::DIV => Some((
+                c,
+                value(a).zip(value(b)).and_then(|(x, y)| x.checked_div(y)),
+            )),
+            Opcode::AND => Some((c, value(a).zip(value(b)).map(|(x, y)| x & y))),
+            Opcode::OR => Some((c, value(a).zip(value(b)).map(|(x, y)| x | y))),
+            Opcode::XOR => Some((c, value(a).zip(value(b)).map(|(x, y)| x ^ y))),
+            Opcode::NOT => Some((b, value(a).map(|x| !x))),
+            Opcode::ADDI => Some((b, value(a).map(|x| x.wrapping_add(c as i8 as i32)))),
+            Opcode::SUBI => Some((b, value(a).map(|x| x.wrapping_sub(c as i8 as i32)))),
+            Opcode::MULI => Some((b, value(a).map(|x| x.wrapping_mul(c as i8 as i32)))),
+            Opcode::INC => Some((a, value(a).map(|x| x.wrapping_add(1)))),
+            Opcode::DEC => Some((a, value(a).map(|x| x.wrapping_sub(1)))),
+            Opcode::REM => Some((a, None)),
+            Opcode::MULW | Opcode::MULWU => {
+                if let Some(high) = known.get_mut(c as usize + 1) {
+                    *high = None;
+                }
+                Some((c, None))
+            }
+            opcode if is_jump(opcode) => {
+                let operand = match opcode.layout() {
+                    Layout::Branch => Some(i16::from_be_bytes([a, b]) as i32),
+                    Layout::RegisterBranch => Some(i16::from_be_bytes([b, c]) as i32),
+                    _ => value(a),
+                };
+                if let Some(target) = operand.and_then(|v| jump_target(pc, opcode, v)) {
+                    targets.insert(pc, target);
+                }
+                known = [None; 32];
+                None
+            }
+            // anything else that writes a register makes it unknown. Float
+            // registers aren't followed
+            opcode => match opcode.layout() {
+                Layout::Binary if !opcode.is_float_register(2) => Some((c, None)),
+                Layout::UnaryResult | Layout::BinaryImmediate if !opcode.is_float_register(1) => {
+                    Some((b, None))
+                }
+                _ => None,
+            },
+        };
+        if let Some((register, v)) = result {
+            if let Some(slot) = known.get_mut(register as usize) {
+                *slot = v;
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_resolve_jumps() {
+        let code = assemble(
+            "load $0 #8
+             jmp $0
+             load $1 #20
+             load $2 #4
+             jmp $1
+             jmpb $2
+             add $0 $1 $3
+             jeq $3
+             load $4 #12
+             shl $4 $4 $4
+             jmp $4
+             load $5 #6
+             not $5 $6
+             not $6 $6
+             jmp $6
+             load $7 #30
+             addi $7 #-6 $7
+             inc $7
+             jmp $7
+             bneq #-76
+             jtable $0 #1
+             load $0 #0
+             jmp $0",
+        )
+        .unwrap()
+        .code;
+        // the jmpb is the target of a jump, so $2 might not be 4 there,
+        // nothing is known after a jump, and shifts aren't followed. Nor is
+        // anything known at a jump table's targets
+        assert_eq!(
+            resolve_jumps(&code),
+            BTreeMap::from([(4, 8), (16, 20), (56, 6), (72, 25), (76, 0)])
+        );
+    }
+}
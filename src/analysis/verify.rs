@@ -0,0 +1,175 @@
+// Checks bytecode is well formed before anything runs it.
+use super::{instructions, jump_table_targets, resolve_jumps};
+use crate::instructions::{Instruction, Opcode};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Problem {
+    /// the opcode byte doesn't name an instruction
+    IllegalOpcode(u8),
+    /// the program ends partway through an instruction
+    TruncatedInstruction,
+    /// a register operand past $31
+    InvalidRegister(u8),
+    /// one of the operand bytes (1 to 3) the instruction doesn't use isn't 0
+    NonZeroPadding { byte: usize, value: u8 },
+    /// a jump whose target is known lands inside an instruction
+    MisalignedJump(i64),
+    /// a jump whose target is known lands outside the program
+    JumpOutOfBounds(i64),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::IllegalOpcode(byte) => write!(f, "illegal opcode {byte}"),
+            Problem::TruncatedInstruction => write!(f, "truncated instruction"),
+            Problem::InvalidRegister(register) => write!(f, "invalid register ${register}"),
+            Problem::NonZeroPadding { byte, value } => {
+                write!(f, "padding byte {byte} is {value}, should be 0")
+            }
+            Problem::MisalignedJump(target) => {
+                write!(f, "jump to {target}, which is inside an instruction")
+            }
+            Problem::JumpOutOfBounds(target) => {
+                write!(f, "jump to {target}, outside the program")
+            }
+        }
+    }
+}
+
+/// A problem with the instruction at `pc`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Finding {
+    pub pc: usize,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.pc, self.problem)
+    }
+}
+
+fn check_instruction(pc: usize, byte: u8, instruction: Instruction, findings: &mut Vec<Finding>) {
+    let mut found = |problem| findings.push(Finding { pc, problem });
+    if instruction.opcode() == Opcode::ILGL {
+        return found(Problem::IllegalOpcode(byte));
+    }
+    let layout = instruction.opcode().layout();
+    let operands = instruction.operands();
+    for &register in &operands[..layout.registers()] {
+        if register >= 32 {
+            found(Problem::InvalidRegister(register));
+        }
+    }
+    // the high word of a wide multiply goes to the register after the output
+    if matches!(instruction.opcode(), Opcode::MULW | Opcode::MULWU) && operands[2] == 31 {
+        found(Problem::InvalidRegister(32));
+    }
+    for (i, &value) in operands.iter().enumerate().skip(layout.used()) {
+        if value != 0 {
+            found(Problem::NonZeroPadding { byte: i + 1, value });
+        }
+    }
+}
+
+/// Everything wrong with `code` that can be seen without running it, in
+/// address order. An empty list means the VM can run it without tripping
+/// over its encoding, though it may still fault on the values it computes.
+pub fn verify(code: &[u8]) -> Vec<Finding> {
+    let mut findings = vec![];
+    let targets = resolve_jumps(code);
+    for (pc, instruction) in instructions(code) {
+        check_instruction(pc, code[pc], instruction, &mut findings);
+        // a jump table's farthest target is just past the table
+        let target = match instruction.opcode() {
+            Opcode::JTABLE => jump_table_targets(pc, instruction).last().map(|t| t as i64),
+            _ => targets.get(&pc).copied(),
+        };
+        match target.as_ref() {
+            Some(&target) if target < 0 || target > code.len() as i64 => findings.push(Finding {
+                pc,
+                problem: Problem::JumpOutOfBounds(target),
+            }),
+            Some(&target) if target % 4 != 0 => findings.push(Finding {
+                pc,
+                problem: Problem::MisalignedJump(target),
+            }),
+            _ => (),
+        }
+    }
+    if !code.len().is_multiple_of(4) {
+        findings.push(Finding {
+            pc: code.len() / 4 * 4,
+            problem: Problem::TruncatedInstruction,
+        });
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_clean_program() {
+        let code = assemble(
+            "load $0 #16
+             load $1 #2
+             eq $0 $1
+             jeq $0
+             add $0 $1 $2
+             jmpf $1",
+        )
+        .unwrap()
+        .code;
+        assert_eq!(verify(&code), []);
+    }
+
+    #[test]
+    fn test_findings() {
+        let code = [
+            1, 40, 0, 1, // load $40 #1
+            9, 0, 1, 7, // eq $0 $1, padding 7
+            0, 0, 0, 1, // hlt, padding 1
+            99, 0, 0, 0, // no such opcode
+            2, 0, 33, 34, // add $0 $33 $34
+            1, 5, 0, 30, // load $5 #30
+            6, 5, 0, 0, // jmp $5, inside the jmpb
+            1, 6, 0, 1, // load $6 #1
+            8, 6, 0, 0, // jmpb $6, to 33
+            1, 0, // half an instruction
+        ];
+        let problems: Vec<(usize, Problem)> = verify(&code)
+            .into_iter()
+            .map(|finding| (finding.pc, finding.problem))
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                (0, Problem::InvalidRegister(40)),
+                (4, Problem::NonZeroPadding { byte: 3, value: 7 }),
+                (8, Problem::NonZeroPadding { byte: 3, value: 1 }),
+                (12, Problem::IllegalOpcode(99)),
+                (16, Problem::InvalidRegister(33)),
+                (16, Problem::InvalidRegister(34)),
+                (24, Problem::MisalignedJump(30)),
+                (32, Problem::MisalignedJump(33)),
+                (36, Problem::TruncatedInstruction),
+            ]
+        );
+        assert_eq!(
+            verify(&[1, 0, 0, 100, 6, 0, 0, 0])[0].to_string(),
+            "4: jump to 100, outside the program"
+        );
+        assert_eq!(verify(&[53, 0, 1, 31])[0].problem, Problem::InvalidRegister(32));
+        // the table has to fit in the program, the default can be its end
+        assert_eq!(verify(&[70, 0, 0, 1, 36, 0, 4, 0]), []);
+        assert_eq!(
+            verify(&[70, 0, 0, 2, 36, 0, 4, 0])[0].problem,
+            Problem::JumpOutOfBounds(12)
+        );
+    }
+}
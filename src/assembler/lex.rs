@@ -2,19 +2,19 @@ use crate::assembler::token::{Lexer, Token};
 use crate::instructions::Opcode;
 use std::iter::{FromIterator, IntoIterator};
 type Op = Opcode;
-type Tok = Token;
+type Tok<'a> = Token<'a>;
 
 #[derive(Debug, PartialEq)]
-pub struct Instruction {
-    operator: Token,
-    operands: Operands,
+pub struct Instruction<'a> {
+    operator: Token<'a>,
+    operands: Operands<'a>,
 }
 
 #[derive(Debug, PartialEq)]
-struct Operands([Option<Token>; 3]);
+struct Operands<'a>([Option<Token<'a>>; 3]);
 
-impl FromIterator<Option<Token>> for Operands {
-    fn from_iter<I: IntoIterator<Item = Option<Token>>>(iter: I) -> Self {
+impl<'a> FromIterator<Option<Token<'a>>> for Operands<'a> {
+    fn from_iter<I: IntoIterator<Item = Option<Token<'a>>>>(iter: I) -> Self {
         let mut iter = iter.into_iter();
         let mut token = || iter.next().unwrap_or(None);
         Operands([token(), token(), token()])
@@ -22,13 +22,13 @@ impl FromIterator<Option<Token>> for Operands {
 }
 
 
-impl Lexer<'_> {
-    fn read_instruction(&mut self) -> Instruction {
+impl<'a> Lexer<'a> {
+    fn read_instruction(&mut self) -> Instruction<'a> {
         let token = self.next_token();
         match token {
             Tok::Operator(Op::LOAD) => Instruction {
                 operator: self.next_token(),
-                operands: self.map(|operand| Some(operand)).collect::<Operands>(),
+                operands: self.map(Some).collect::<Operands>(),
             },
             _ => panic!("unimplemented"),
         }
@@ -36,7 +36,7 @@ impl Lexer<'_> {
 }
 
 #[derive(Debug, PartialEq)]
-pub struct Program(Vec<Instruction>);
+pub struct Program<'a>(Vec<Instruction<'a>>);
 
 fn parse_integer(i: i32) -> (u8, u8) {
     let right = i as u16;
@@ -44,7 +44,7 @@ fn parse_integer(i: i32) -> (u8, u8) {
     (left as u8, right as u8)
 }
 
-impl Token {
+impl Token<'_> {
     fn parse_operand(self, operands: &mut Vec<u8>) -> Result<(), &'static str> {
         match self {
             Token::Register(address) => operands.push(address),
@@ -59,15 +59,13 @@ impl Token {
     }
 }
 
-impl Instruction {
+impl Instruction<'_> {
     pub fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
         if let Token::Operator(op) = self.operator {
             let mut bytes = vec![op as u8];
             let Operands(ops) = self.operands;
-            for operand in ops.into_iter() {
-                if let Some(token) = operand {
-                    token.parse_operand(&mut bytes)?
-                }
+            for token in ops.into_iter().flatten() {
+                token.parse_operand(&mut bytes)?
             }
             Ok(bytes)
         } else {
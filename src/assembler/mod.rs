@@ -1,3 +1,341 @@
+#[allow(dead_code)]
 mod lex;
 mod token;
-pub mod parser;
\ No newline at end of file
+pub mod parser;
+
+use parser::Parser;
+use std::fmt;
+
+/// Assembled bytecode, plus where each instruction came from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub code: Vec<u8>,
+    /// source line of every instruction, in address order
+    pub lines: Vec<usize>,
+    /// the float constants LOADF loads, by index
+    pub constants: Vec<f64>,
+    /// what the heap starts out as, the NUL-terminated string literals
+    pub data: Vec<u8>,
+}
+
+impl Program {
+    /// Source line of the instruction at `pc`.
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.lines.get(pc / 4).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblerError {
+    pub line: usize,
+    pub column: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+pub fn assemble(source: &str) -> Result<Program, AssemblerError> {
+    let mut program = Program::default();
+    let mut parser = Parser::new(source);
+    for instruction in &mut parser {
+        let (bytes, line) = instruction?;
+        program.code.extend(bytes);
+        program.lines.push(line);
+    }
+    parser.resolve_labels(&mut program.code)?;
+    program.constants = parser.constants().to_vec();
+    program.data = parser.data().to_vec();
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let source = "load $0 #500\n\
+                      load $1 #2 ; comment\n\
+                      \n\
+                      div $0 $1 $2\n\
+                      gteq $2 $0\n\
+                      jeq $3\n\
+                      hlt";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program.code,
+            vec![1, 0, 1, 244, 1, 1, 0, 2, 5, 0, 1, 2, 13, 2, 0, 0, 15, 3, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(program.lines, vec![1, 2, 4, 5, 6, 7]);
+        assert_eq!(program.line(9), Some(4));
+        assert_eq!(program.line(24), None);
+        let program = assemble("and $0 $1 $2\nnot $2 $3\nsar $3 $0 $4").unwrap();
+        assert_eq!(program.code, vec![17, 0, 1, 2, 20, 2, 3, 0, 23, 3, 0, 4]);
+    }
+
+    #[test]
+    fn test_immediates() {
+        // an integer second operand picks the immediate form
+        let program = assemble(
+            "add $0 #-1 $2
+             subi $1 #127 $1
+             lt $3 #-300
+             gteqi $3 #0
+             inc $4",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            vec![24, 0, 2, 255, 25, 1, 1, 127, 30, 3, 254, 212, 31, 3, 0, 0, 33, 4, 0, 0]
+        );
+        let error = |source| assemble(source).unwrap_err().message;
+        assert_eq!(error("add $0 #128 $1"), "syntax error: integer does not fit in 8 bits");
+        assert_eq!(error("eq $0 #-32769"), "syntax error: integer does not fit in 16 bits");
+        assert_eq!(error("load $0 #-1"), "syntax error: integer does not fit in 16 bits");
+        assert_eq!(error("addi $0 $1 $2"), "syntax error: expected integer, found register");
+        assert_eq!(error("and $0 #1 $2"), "syntax error: expected register, found integer");
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = assemble(
+            "        load $0 #3
+             loop:   dec $0
+                     eqi $0 #0
+                     jneq @loop
+                     jmp @end
+                     load $1 @loop
+             end:
+             done:   hlt",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            vec![
+                1, 0, 0, 3, 34, 0, 0, 0, 27, 0, 0, 0, 38, 255, 248, 0, 36, 0, 8, 0, 1, 1, 0, 4, 0,
+                0, 0, 0
+            ]
+        );
+        assert_eq!(program.lines, vec![1, 2, 3, 4, 5, 6, 8]);
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            error("hlt\njmp @nowhere"),
+            AssemblerError {
+                line: 2,
+                column: 5,
+                message: "syntax error: unknown label"
+            }
+        );
+        assert_eq!(error("a: hlt\na: hlt").message, "syntax error: label is already defined");
+        assert_eq!(error("jmpf @a\na: hlt").message, "syntax error: expected register, found label");
+        let far = format!("bra @far\n{}far: hlt", "hlt\n".repeat(9000));
+        assert_eq!(error(&far).message, "syntax error: label is too far away to branch to");
+        assert_eq!(assemble("beq #-8").unwrap().code, vec![37, 255, 248, 0]);
+    }
+
+    #[test]
+    fn test_floats() {
+        let program = assemble(
+            "loadf $f0 #1.5
+             loadf $f1 #-2
+             loadf $f2 #1.5
+             addf $f0 $f1 $f2
+             lteqf $f2 $f0
+             itof $3 $f4
+             ftoi $f4 $3",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            vec![
+                57, 0, 0, 0, 57, 1, 0, 1, 57, 2, 0, 0, 58, 0, 1, 2, 67, 2, 0, 0, 68, 3, 4, 0, 69,
+                4, 3, 0
+            ]
+        );
+        assert_eq!(program.constants, vec![1.5, -2.0]);
+        let error = |source| assemble(source).unwrap_err().message;
+        assert_eq!(
+            error("addf $f0 $1 $f2"),
+            "syntax error: expected float register, found integer register"
+        );
+        assert_eq!(
+            error("itof $f0 $f1"),
+            "syntax error: expected integer register, found float register"
+        );
+        assert_eq!(error("load $0 #1.5"), "syntax error: expected integer, found float");
+        assert_eq!(error("eqf $f0 #1.5"), "syntax error: expected register, found float");
+    }
+
+    #[test]
+    fn test_jump_tables() {
+        // the table goes on over the next line, so it has three entries, and
+        // the bra right after it is the default
+        let program = assemble(
+            "        jtable $0 #3
+             table:  .jumptable @zero @one
+                     @zero
+                     bra @default
+             zero:   load $1 #0
+             one:    load $1 #1
+             default:
+                     hlt",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            vec![
+                70, 0, 0, 3, 36, 0, 16, 0, 36, 0, 16, 0, 36, 0, 8, 0, 36, 0, 12, 0, 1, 1, 0, 0, 1,
+                1, 0, 1, 0, 0, 0, 0
+            ]
+        );
+        assert_eq!(program.lines, vec![1, 2, 2, 3, 4, 5, 6, 8]);
+        let error = |source| assemble(source).unwrap_err().message;
+        assert_eq!(error(".nope @a"), "syntax error: unknown directive");
+        assert_eq!(
+            error(".jumptable @a\nhlt\n@a\na: hlt"),
+            "syntax error: expected an instruction"
+        );
+        assert_eq!(
+            error("jtable .jumptable"),
+            "syntax error: expected register, found directive"
+        );
+        let mismatch = "syntax error: jtable count does not match the entries of its .jumptable";
+        assert_eq!(
+            assemble("jtable $0 #2\n.jumptable @a\na: hlt").unwrap_err(),
+            AssemblerError {
+                line: 1,
+                column: 11,
+                message: mismatch
+            }
+        );
+        assert_eq!(error("jtable $0 #1\nt: .jumptable @a @a\na: hlt"), mismatch);
+        assert_eq!(error("jtable $0 #3\n.jumptable @a @a"), mismatch);
+        // a table of hand-written branches isn't checked
+        assert!(assemble("jtable $0 #2\nbra @a\nhlt\na: hlt").is_ok());
+    }
+
+    #[test]
+    fn test_loops() {
+        let program = assemble(
+            "      load $0 #3
+             top:  dec $1
+                   loop $0 @top
+                   loop $0 #-4",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            vec![1, 0, 0, 3, 34, 1, 0, 0, 71, 0, 255, 252, 71, 0, 255, 252]
+        );
+        let nested = assemble(
+            "load $0 #2
+             .loop $0
+                 load $1 #3
+                 .loop $1
+                     inc $2
+                 .endloop
+             .endloop
+             hlt",
+        )
+        .unwrap();
+        let labelled = assemble(
+            "        load $0 #2
+             outer:  load $1 #3
+             inner:  inc $2
+                     loop $1 @inner
+                     loop $0 @outer
+                     hlt",
+        )
+        .unwrap();
+        assert_eq!(nested.code, labelled.code);
+        assert_eq!(nested.lines, vec![1, 3, 5, 6, 7, 8]);
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            error(".endloop").message,
+            "syntax error: .endloop without a .loop"
+        );
+        assert_eq!(
+            error("hlt\n.loop $0\nhlt"),
+            AssemblerError {
+                line: 2,
+                column: 1,
+                message: "syntax error: .loop without an .endloop",
+            }
+        );
+        assert_eq!(
+            error(".loop #1").message,
+            "syntax error: expected register, found integer"
+        );
+        assert_eq!(
+            error("loop $0 #40000").message,
+            "syntax error: integer does not fit in 16 bits"
+        );
+        let far = format!(".loop $0\n{}.endloop", "hlt\n".repeat(8200));
+        assert_eq!(
+            error(&far).message,
+            "syntax error: loop is too long to branch back to its start"
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let program = assemble(
+            r#"load $0 "héllo"
+               load $1 "\"hi\"\n"
+               load $2 "héllo"
+               strcat $0 $1 $3"#,
+        )
+        .unwrap();
+        assert_eq!(program.data, "héllo\0\"hi\"\n\0".as_bytes());
+        assert_eq!(
+            program.code,
+            vec![1, 0, 0, 0, 1, 1, 0, 7, 1, 2, 0, 0, 74, 0, 1, 3]
+        );
+        let error = |source| assemble(source).unwrap_err().message;
+        assert_eq!(
+            error(r#"add $0 "x" $1"#),
+            "syntax error: expected register, found string"
+        );
+        assert_eq!(
+            error(r#"bra "x""#),
+            "syntax error: expected integer, found string"
+        );
+        assert_eq!(
+            error(r#"load $0 "open"#),
+            "syntax error: expected integer, found operator"
+        );
+        let big = format!("load $0 \"{}\"\nload $1 \"b\"", "a".repeat(65536));
+        assert_eq!(
+            error(&big),
+            "syntax error: string starts past the first 64 KiB of data"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(
+            error("load $0 #1\nand $0 #1 $2"),
+            AssemblerError {
+                line: 2,
+                column: 8,
+                message: "syntax error: expected register, found integer"
+            }
+        );
+        assert_eq!(error("load #1 #1").column, 6);
+        assert_eq!(error("jmp").message, "syntax error: unexpected end of input");
+        assert_eq!(error("not $1").message, "syntax error: unexpected end of input");
+        assert_eq!(error("frobnicate $1").message, "syntax error: unknown instruction");
+        assert_eq!(error("$1").message, "syntax error: expected an instruction");
+        assert_eq!(
+            error("load $0 #70000").to_string(),
+            "1:9: syntax error: integer does not fit in 16 bits"
+        );
+    }
+}
@@ -1,12 +1,48 @@
-use crate::assembler::token::{Lexer, Token};
-use crate::instructions::Opcode;
+use crate::assembler::token::{unescape, Lexer, Token};
+use crate::assembler::AssemblerError;
+use crate::instructions::{Layout, Opcode};
+use std::collections::HashMap;
 
 mod err {
-    pub const integer_for_register: &'static str = "syntax error: expected register, found integer";
-    pub const operator_for_register: &'static str =
+    pub const INTEGER_FOR_REGISTER: &str = "syntax error: expected register, found integer";
+    pub const OPERATOR_FOR_REGISTER: &str =
         "syntax error: expected register, found operator";
-    pub const register_for_integer: &'static str = "syntax error: expected integer, found register";
-    pub const operator_for_integer: &'static str = "syntax error: expected integer, found operator";
+    pub const REGISTER_FOR_INTEGER: &str = "syntax error: expected integer, found register";
+    pub const OPERATOR_FOR_INTEGER: &str = "syntax error: expected integer, found operator";
+    pub const EOF_FOR_OPERAND: &str = "syntax error: unexpected end of input";
+    pub const EXPECTED_OPERATOR: &str = "syntax error: expected an instruction";
+    pub const UNKNOWN_OPERATOR: &str = "syntax error: unknown instruction";
+    pub const INTEGER_OUT_OF_RANGE: &str = "syntax error: integer does not fit in 16 bits";
+    pub const SMALL_INTEGER_OUT_OF_RANGE: &str = "syntax error: integer does not fit in 8 bits";
+    pub const LABEL_FOR_REGISTER: &str = "syntax error: expected register, found label";
+    pub const LABEL_FOR_INTEGER: &str = "syntax error: expected integer, found label";
+    pub const DUPLICATE_LABEL: &str = "syntax error: label is already defined";
+    pub const UNKNOWN_LABEL: &str = "syntax error: unknown label";
+    pub const LABEL_TOO_FAR: &str = "syntax error: label is too far away to branch to";
+    pub const REGISTER_FOR_FLOAT_REGISTER: &str =
+        "syntax error: expected float register, found integer register";
+    pub const FLOAT_REGISTER_FOR_REGISTER: &str =
+        "syntax error: expected integer register, found float register";
+    pub const FLOAT_FOR_REGISTER: &str = "syntax error: expected register, found float";
+    pub const FLOAT_FOR_INTEGER: &str = "syntax error: expected integer, found float";
+    pub const TOO_MANY_CONSTANTS: &str = "syntax error: more than 65536 float constants";
+    pub const DIRECTIVE_FOR_REGISTER: &str = "syntax error: expected register, found directive";
+    pub const DIRECTIVE_FOR_INTEGER: &str = "syntax error: expected integer, found directive";
+    pub const UNKNOWN_DIRECTIVE: &str = "syntax error: unknown directive";
+    pub const ENDLOOP_WITHOUT_LOOP: &str = "syntax error: .endloop without a .loop";
+    pub const UNCLOSED_LOOP: &str = "syntax error: .loop without an .endloop";
+    pub const LOOP_TOO_LONG: &str = "syntax error: loop is too long to branch back to its start";
+    pub const STRING_FOR_REGISTER: &str = "syntax error: expected register, found string";
+    pub const STRING_FOR_INTEGER: &str = "syntax error: expected integer, found string";
+    pub const TOO_MUCH_DATA: &str = "syntax error: string starts past the first 64 KiB of data";
+    pub const JUMP_TABLE_SIZE: &str =
+        "syntax error: jtable count does not match the entries of its .jumptable";
+}
+
+mod directive {
+    pub const JUMPTABLE: &str = "jumptable";
+    pub const LOOP: &str = "loop";
+    pub const ENDLOOP: &str = "endloop";
 }
 
 fn parse_integer(i: i32) -> (u8, u8) {
@@ -15,73 +51,446 @@ fn parse_integer(i: i32) -> (u8, u8) {
     (left as u8, right as u8)
 }
 
-struct Parser<'a> {
+// A use of a label, filled in once every label is known.
+struct LabelUsage<'a> {
+    label: &'a str,
+    // address of the instruction using it
+    address: usize,
+    // branches take an offset from their own address, anything else the
+    // address itself
+    relative: bool,
+    // the operand byte the two bytes of the label go in
+    operand: usize,
+    line: usize,
+    column: usize,
+}
+
+pub struct Parser<'a> {
     lexer: Lexer<'a>,
+    // line of the instruction being parsed
+    line: usize,
+    // address of the instruction being parsed
+    address: usize,
+    labels: HashMap<&'a str, usize>,
+    usages: Vec<LabelUsage<'a>>,
+    // the float literals seen so far, each once
+    constants: Vec<f64>,
+    // whether label usages are the entries of a `.jumptable`
+    jump_table: bool,
+    // count and position of the JTABLE just parsed, which a `.jumptable`
+    // right after it has to have as many entries as
+    jtable: Option<(usize, (usize, usize))>,
+    // the same for the JTABLE whose `.jumptable` is being parsed, with the
+    // entries seen so far
+    table: Option<(usize, usize, (usize, usize))>,
+    // start address, counter and position of each `.loop` not closed yet
+    loops: Vec<(usize, u8, (usize, usize))>,
+    // the string literals seen so far, each once and NUL-terminated
+    data: Vec<u8>,
+    // where each string is in `data`, by its spelling in the source
+    strings: HashMap<&'a str, usize>,
 } // is this wrapper **really**¨ necessary?
 
+/// Yields each assembled instruction together with the source line it came from.
 impl<'a> Iterator for Parser<'a> {
-    type Item = [u8; 4];
+    type Item = Result<([u8; 4], usize), AssemblerError>;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.next_instruction() {
-            Ok(instruction) => Some(instruction),
-            Err(e) => panic!("{e}"),
-        }
+        self.next_instruction()
+            .map(|instruction| instruction.map(|instruction| (instruction, self.line)))
+            .transpose()
     }
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(input: &'a String) -> Self {
+    pub fn new(input: &'a str) -> Self {
         Parser {
             lexer: Lexer::new(input),
+            line: 0,
+            address: 0,
+            labels: HashMap::new(),
+            usages: vec![],
+            constants: vec![],
+            jump_table: false,
+            jtable: None,
+            table: None,
+            loops: vec![],
+            data: vec![],
+            strings: HashMap::new(),
+        }
+    }
+
+    /// The constant pool the LOADFs parsed so far index into.
+    pub fn constants(&self) -> &[f64] {
+        &self.constants
+    }
+
+    /// The data segment the strings parsed so far point into.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Fills in the labels used in `code`, which has to be everything the
+    /// parser produced.
+    pub fn resolve_labels(&self, code: &mut [u8]) -> Result<(), AssemblerError> {
+        for usage in &self.usages {
+            let error = |message| AssemblerError {
+                line: usage.line,
+                column: usage.column,
+                message,
+            };
+            let target = *self.labels.get(usage.label).ok_or(error(err::UNKNOWN_LABEL))?;
+            let bytes = if usage.relative {
+                let offset = i16::try_from(target as i64 - usage.address as i64)
+                    .map_err(|_| error(err::LABEL_TOO_FAR))?;
+                offset.to_be_bytes()
+            } else {
+                let target = u16::try_from(target).map_err(|_| error(err::INTEGER_OUT_OF_RANGE))?;
+                target.to_be_bytes()
+            };
+            let at = usage.address + 1 + usage.operand;
+            code[at..at + 2].copy_from_slice(&bytes);
+        }
+        Ok(())
+    }
+
+    fn use_label(&mut self, label: &'a str, relative: bool, operand: usize) {
+        let (line, column) = self.lexer.position();
+        self.usages.push(LabelUsage {
+            label,
+            address: self.address,
+            relative,
+            operand,
+            line,
+            column,
+        });
+    }
+
+    fn error(&self, message: &'static str) -> AssemblerError {
+        let (line, column) = self.lexer.position();
+        AssemblerError {
+            line,
+            column,
+            message,
+        }
+    }
+
+    // a register from the bank operand `i` of `op` names
+    fn register(&mut self, op: Opcode, i: usize) -> Result<u8, AssemblerError> {
+        let token = self.lexer.next_token();
+        self.expect_register(token, op.is_float_register(i))
+    }
+
+    fn expect_register(&self, token: Token, float: bool) -> Result<u8, AssemblerError> {
+        match token {
+            Token::Register(address) if !float => Ok(address),
+            Token::FloatRegister(address) if float => Ok(address),
+            token => Err(self.not_a_register(token)),
         }
     }
 
-    fn register(&mut self) -> Result<u8, &'static str> {
+    // what's wrong with a token that should have been a register
+    fn not_a_register(&self, token: Token) -> AssemblerError {
+        self.error(match token {
+            Token::Register(_) => err::REGISTER_FOR_FLOAT_REGISTER,
+            Token::FloatRegister(_) => err::FLOAT_REGISTER_FOR_REGISTER,
+            Token::Integer(_) => err::INTEGER_FOR_REGISTER,
+            Token::Float(_) => err::FLOAT_FOR_REGISTER,
+            Token::Operator(_) => err::OPERATOR_FOR_REGISTER,
+            Token::Directive(_) => err::DIRECTIVE_FOR_REGISTER,
+            Token::String(_) => err::STRING_FOR_REGISTER,
+            Token::Label(_) | Token::LabelUsage(_) => err::LABEL_FOR_REGISTER,
+            Token::EOF => err::EOF_FOR_OPERAND,
+        })
+    }
+
+    // a 16-bit integer, the address of a label, or the heap address of a
+    // string
+    fn integer(&mut self) -> Result<(u8, u8), AssemblerError> {
         match self.lexer.next_token() {
-            Token::Register(address) => Ok(address),
-            Token::Integer(_) => Err(err::integer_for_register),
-            Token::Operator(_) => Err(err::operator_for_register),
-            _ => Err("EOF"),
+            Token::Integer(value) if (0..=u16::MAX as i32).contains(&value) => {
+                Ok(parse_integer(value))
+            }
+            Token::LabelUsage(label) => {
+                self.use_label(label, false, 1);
+                Ok((0, 0))
+            }
+            Token::String(text) => self.string(text),
+            token => Err(self.not_an_integer(token, err::INTEGER_OUT_OF_RANGE)),
         }
     }
 
-    fn integer(&mut self) -> Result<(u8, u8), &'static str> {
+    // a signed 16-bit offset, or the offset to a label, for operand byte
+    // `operand`
+    fn offset(&mut self, operand: usize) -> Result<(u8, u8), AssemblerError> {
         match self.lexer.next_token() {
-            Token::Integer(value) => Ok(parse_integer(value)),
-            Token::Register(_) => Err(err::register_for_integer),
-            Token::Operator(_) => Err(err::operator_for_integer),
-            _ => Err("EOF"),
+            Token::Integer(value) if i16::try_from(value).is_ok() => Ok(parse_integer(value)),
+            Token::LabelUsage(label) => {
+                self.use_label(label, true, operand);
+                Ok((0, 0))
+            }
+            token => Err(self.not_an_integer(token, err::INTEGER_OUT_OF_RANGE)),
         }
     }
 
-    fn integer_op(&mut self, op: Opcode) -> Result<[u8; 4], &'static str> {
-        let op = op as u8;
-        let output_address = self.register()?;
-        let (left_byte, right_byte) = self.integer()?;
-        Ok([op, output_address, left_byte, right_byte])
+    // what's wrong with a token that should have been an integer, when the
+    // integer is out of range
+    fn not_an_integer(&self, token: Token, out_of_range: &'static str) -> AssemblerError {
+        self.error(match token {
+            Token::Integer(_) => out_of_range,
+            Token::Register(_) | Token::FloatRegister(_) => err::REGISTER_FOR_INTEGER,
+            Token::Float(_) => err::FLOAT_FOR_INTEGER,
+            Token::Operator(_) => err::OPERATOR_FOR_INTEGER,
+            Token::Directive(_) => err::DIRECTIVE_FOR_INTEGER,
+            Token::String(_) => err::STRING_FOR_INTEGER,
+            Token::Label(_) | Token::LabelUsage(_) => err::LABEL_FOR_INTEGER,
+            Token::EOF => err::EOF_FOR_OPERAND,
+        })
+    }
+
+    // a number for LOADF, as its index in the constant pool
+    fn constant(&mut self) -> Result<(u8, u8), AssemblerError> {
+        let value = match self.lexer.next_token() {
+            Token::Float(value) => value,
+            Token::Integer(value) => value as f64,
+            token => return Err(self.not_an_integer(token, err::INTEGER_OUT_OF_RANGE)),
+        };
+        let same = |c: &f64| c.to_bits() == value.to_bits();
+        let index = match self.constants.iter().position(same) {
+            Some(index) => index,
+            None => {
+                self.constants.push(value);
+                self.constants.len() - 1
+            }
+        };
+        match u16::try_from(index) {
+            Ok(index) => Ok(parse_integer(index as i32)),
+            Err(_) => Err(self.error(err::TOO_MANY_CONSTANTS)),
+        }
     }
 
-    fn nullary_op(&mut self, op: Opcode) -> Result<[u8; 4], &'static str> {
+    // where a string is in the data segment, which the heap starts out as
+    fn string(&mut self, text: &'a str) -> Result<(u8, u8), AssemblerError> {
+        let address = match self.strings.get(text) {
+            Some(&address) => address,
+            None => {
+                let address = self.data.len();
+                self.data.extend(unescape(text).bytes());
+                self.data.push(0);
+                self.strings.insert(text, address);
+                address
+            }
+        };
+        match u16::try_from(address) {
+            Ok(address) => Ok(parse_integer(address as i32)),
+            Err(_) => Err(self.error(err::TOO_MUCH_DATA)),
+        }
+    }
+
+    fn integer_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
+        let output_address = self.register(op, 0)?;
+        let (left_byte, right_byte) = match op {
+            Opcode::LOADF => self.constant()?,
+            _ => self.integer()?,
+        };
+        if op == Opcode::JTABLE {
+            let count = u16::from_be_bytes([left_byte, right_byte]) as usize;
+            self.jtable = Some((count, self.lexer.position()));
+        }
+        Ok([op as u8, output_address, left_byte, right_byte])
+    }
+
+    fn nullary_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
         let op = op as u8;
         Ok([op, 0, 0, 0])
     }
 
-    fn binary_op(&mut self, op: Opcode) -> Result<[u8; 4], &'static str> {
+    // jumps to a label become relative branches
+    fn unary_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
+        match (self.lexer.next_token(), op.branch()) {
+            (Token::LabelUsage(label), Some(branch)) => {
+                self.use_label(label, true, 0);
+                Ok([branch as u8, 0, 0, 0])
+            }
+            (token, _) => {
+                let register = self.expect_register(token, op.is_float_register(0))?;
+                Ok([op as u8, register, 0, 0])
+            }
+        }
+    }
+
+    fn branch_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
         let op = op as u8;
-        let left_operand = self.register()?;
-        let right_operand = self.register()?;
-        let output_address = self.register()?;
-        Ok([op, left_operand, right_operand, output_address])
+        let (left_byte, right_byte) = self.offset(0)?;
+        Ok([op, left_byte, right_byte, 0])
     }
 
-    fn next_instruction(&mut self) -> Result<[u8; 4], &'static str> {
-        let token = self.lexer.next_token();
+    fn register_branch_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
+        let register = self.register(op, 0)?;
+        let (left_byte, right_byte) = self.offset(1)?;
+        Ok([op as u8, register, left_byte, right_byte])
+    }
 
-        match token {
-            Token::Operator(Opcode::LOAD) => self.integer_op(Opcode::LOAD),
-            Token::Operator(Opcode::ADD) => self.binary_op(Opcode::ADD),
-            Token::Operator(Opcode::SUB) => self.binary_op(Opcode::SUB),
-            _ => Err("EOF"),
+    // `.loop $r` starts a loop body that runs for as many times as the
+    // counter in `$r` says, `.endloop` ends it with a LOOP back to the start.
+    // The body always runs once before the counter is checked, so the
+    // counter has to be positive: 0 means 2^32 passes, and a negative one
+    // wraps around just the same
+    fn start_loop(&mut self) -> Result<(), AssemblerError> {
+        let position = self.lexer.position();
+        let register = self.register(Opcode::LOOP, 0)?;
+        self.loops.push((self.address, register, position));
+        Ok(())
+    }
+
+    fn end_loop(&mut self) -> Result<[u8; 4], AssemblerError> {
+        let (start, register, _) = self
+            .loops
+            .pop()
+            .ok_or(self.error(err::ENDLOOP_WITHOUT_LOOP))?;
+        match i16::try_from(start as i64 - self.address as i64) {
+            Ok(offset) => {
+                let [left_byte, right_byte] = offset.to_be_bytes();
+                Ok([Opcode::LOOP as u8, register, left_byte, right_byte])
+            }
+            Err(_) => Err(self.error(err::LOOP_TOO_LONG)),
+        }
+    }
+
+    // The second operand of an instruction that may have an immediate form.
+    // An integer there picks that form, which immediate instructions need.
+    fn second_operand(&mut self, op: Opcode) -> Result<(Opcode, i32), AssemblerError> {
+        let immediate = op.immediate();
+        let float = op.is_float_register(1);
+        match self.lexer.next_token() {
+            Token::Register(address) if immediate != Some(op) && !float => Ok((op, address as i32)),
+            Token::FloatRegister(address) if float => Ok((op, address as i32)),
+            Token::Integer(value) => match immediate {
+                Some(op) if op.layout() == Layout::BinaryImmediate => match i8::try_from(value) {
+                    Ok(_) => Ok((op, value)),
+                    Err(_) => Err(self.error(err::SMALL_INTEGER_OUT_OF_RANGE)),
+                },
+                Some(op) => match i16::try_from(value) {
+                    Ok(_) => Ok((op, value)),
+                    Err(_) => Err(self.error(err::INTEGER_OUT_OF_RANGE)),
+                },
+                None => Err(self.error(err::INTEGER_FOR_REGISTER)),
+            },
+            Token::Register(_) if !float => Err(self.error(err::REGISTER_FOR_INTEGER)),
+            token => Err(self.not_a_register(token)),
         }
     }
+
+    fn comparison_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
+        let left_operand = self.register(op, 0)?;
+        let (op, right_operand) = self.second_operand(op)?;
+        match op.layout() {
+            Layout::ComparisonImmediate => {
+                let (left_byte, right_byte) = parse_integer(right_operand);
+                Ok([op as u8, left_operand, left_byte, right_byte])
+            }
+            _ => Ok([op as u8, left_operand, right_operand as u8, 0]),
+        }
+    }
+
+    fn binary_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
+        let left_operand = self.register(op, 0)?;
+        let (op, right_operand) = self.second_operand(op)?;
+        let output = match op.layout() {
+            Layout::BinaryImmediate => 1,
+            _ => 2,
+        };
+        let output_address = self.register(op, output)?;
+        match op.layout() {
+            Layout::BinaryImmediate => {
+                Ok([op as u8, left_operand, output_address, right_operand as u8])
+            }
+            _ => Ok([op as u8, left_operand, right_operand as u8, output_address]),
+        }
+    }
+
+    fn unary_result_op(&mut self, op: Opcode) -> Result<[u8; 4], AssemblerError> {
+        let operand = self.register(op, 0)?;
+        let output_address = self.register(op, 1)?;
+        Ok([op as u8, operand, output_address, 0])
+    }
+
+    // Counts an entry of the `.jumptable` following a JTABLE, or checks the
+    // count once the table is over
+    fn count_entry(&mut self) -> Result<(), AssemblerError> {
+        match (self.jump_table, self.table.take()) {
+            (true, Some((count, entries, position))) => {
+                self.table = Some((count, entries + 1, position))
+            }
+            (false, Some((count, entries, (line, column)))) if count != entries => {
+                return Err(AssemblerError {
+                    line,
+                    column,
+                    message: err::JUMP_TABLE_SIZE,
+                })
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn next_instruction(&mut self) -> Result<Option<[u8; 4]>, AssemblerError> {
+        let jtable = self.jtable.take();
+        let mut token = self.lexer.next_token();
+        loop {
+            match token {
+                Token::Label(label) => {
+                    if self.labels.insert(label, self.address).is_some() {
+                        return Err(self.error(err::DUPLICATE_LABEL));
+                    }
+                }
+                Token::Directive(directive::JUMPTABLE) => {
+                    self.jump_table = true;
+                    if let Some((count, position)) = jtable {
+                        self.table = Some((count, 0, position));
+                    }
+                }
+                Token::Directive(directive::LOOP) => self.start_loop()?,
+                Token::Directive(directive::ENDLOOP) => break,
+                Token::Directive(_) => return Err(self.error(err::UNKNOWN_DIRECTIVE)),
+                _ => break,
+            }
+            token = self.lexer.next_token();
+        }
+        self.line = self.lexer.position().0;
+        self.jump_table &= matches!(token, Token::LabelUsage(_));
+        self.count_entry()?;
+
+        let instruction = match token {
+            Token::EOF => match self.loops.last() {
+                Some(&(_, _, (line, column))) => Err(AssemblerError {
+                    line,
+                    column,
+                    message: err::UNCLOSED_LOOP,
+                }),
+                None => return Ok(None),
+            },
+            // each entry of a jump table is a branch to its label. An index
+            // past the table lands on whatever comes after the last entry,
+            // so that is where the default case goes
+            Token::LabelUsage(label) if self.jump_table => {
+                self.use_label(label, true, 0);
+                Ok([Opcode::BRA as u8, 0, 0, 0])
+            }
+            Token::Operator(Opcode::ILGL) => Err(self.error(err::UNKNOWN_OPERATOR)),
+            Token::Operator(op) => match op.layout() {
+                Layout::Nullary => self.nullary_op(op),
+                Layout::RegisterInteger => self.integer_op(op),
+                Layout::Unary => self.unary_op(op),
+                Layout::Comparison | Layout::ComparisonImmediate => self.comparison_op(op),
+                Layout::Binary | Layout::BinaryImmediate => self.binary_op(op),
+                Layout::UnaryResult => self.unary_result_op(op),
+                Layout::Branch => self.branch_op(op),
+                Layout::RegisterBranch => self.register_branch_op(op),
+            },
+            Token::Directive(directive::ENDLOOP) => self.end_loop(),
+            _ => Err(self.error(err::EXPECTED_OPERATOR)),
+        };
+        self.address += 4;
+        instruction.map(Some)
+    }
 }
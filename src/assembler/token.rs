@@ -1,15 +1,29 @@
 use crate::instructions::Opcode;
 use std::str::Chars;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Token {
+pub enum Token<'a> {
     Operator(Opcode),
     Register(u8),
+    /// `$f0`, one of the float registers
+    FloatRegister(u8),
     Integer(i32),
+    /// `#1.5`, a number with a decimal point
+    Float(f64),
+    /// `name:`, which names the address of the next instruction
+    Label(&'a str),
+    /// `@name`, the address of a label
+    LabelUsage(&'a str),
+    /// `.name`, which tells the assembler to do something other than
+    /// assemble an instruction
+    Directive(&'a str),
+    /// `"text"`, with its escapes still in it
+    String(&'a str),
     EOF,
 }
 
-impl From<&str> for Token {
+impl From<&str> for Token<'_> {
     fn from(v: &str) -> Self {
         Token::Operator(Opcode::from(v))
     }
@@ -17,8 +31,7 @@ impl From<&str> for Token {
 
 impl From<&str> for Opcode {
     fn from(v: &str) -> Self {
-       
-        match v {
+        match v.to_lowercase().as_str() {
             operator::HLT => Opcode::HLT,
             operator::LOAD => Opcode::LOAD,
             operator::ADD => Opcode::ADD,
@@ -31,53 +44,211 @@ impl From<&str> for Opcode {
             operator::EQ => Opcode::EQ,
             operator::NEQ => Opcode::NEQ,
             operator::GT => Opcode::GT,
-            operator::LT => Opcode::GT,
-            operator::GTEQ => Opcode::GT,
+            operator::LT => Opcode::LT,
+            operator::GTEQ => Opcode::GTEQ,
             operator::LTEQ => Opcode::LTEQ,
-            operator::JEQ => Opcode::LTEQ,
+            operator::JEQ => Opcode::JEQ,
+            operator::ALLOC => Opcode::ALLOC,
+            operator::AND => Opcode::AND,
+            operator::OR => Opcode::OR,
+            operator::XOR => Opcode::XOR,
+            operator::NOT => Opcode::NOT,
+            operator::SHL => Opcode::SHL,
+            operator::SHR => Opcode::SHR,
+            operator::SAR => Opcode::SAR,
+            operator::ADDI => Opcode::ADDI,
+            operator::SUBI => Opcode::SUBI,
+            operator::MULI => Opcode::MULI,
+            operator::EQI => Opcode::EQI,
+            operator::NEQI => Opcode::NEQI,
+            operator::GTI => Opcode::GTI,
+            operator::LTI => Opcode::LTI,
+            operator::GTEQI => Opcode::GTEQI,
+            operator::LTEQI => Opcode::LTEQI,
+            operator::INC => Opcode::INC,
+            operator::DEC => Opcode::DEC,
+            operator::JNEQ => Opcode::JNEQ,
+            operator::BRA => Opcode::BRA,
+            operator::BEQ => Opcode::BEQ,
+            operator::BNEQ => Opcode::BNEQ,
+            operator::BZ => Opcode::BZ,
+            operator::BNZ => Opcode::BNZ,
+            operator::BN => Opcode::BN,
+            operator::BNN => Opcode::BNN,
+            operator::BC => Opcode::BC,
+            operator::BNC => Opcode::BNC,
+            operator::BV => Opcode::BV,
+            operator::BNV => Opcode::BNV,
+            operator::DIVU => Opcode::DIVU,
+            operator::GTU => Opcode::GTU,
+            operator::LTU => Opcode::LTU,
+            operator::GTEQU => Opcode::GTEQU,
+            operator::LTEQU => Opcode::LTEQU,
+            operator::REM => Opcode::REM,
+            operator::MULW => Opcode::MULW,
+            operator::MULWU => Opcode::MULWU,
+            operator::ADC => Opcode::ADC,
+            operator::SBC => Opcode::SBC,
+            operator::LOADF => Opcode::LOADF,
+            operator::ADDF => Opcode::ADDF,
+            operator::SUBF => Opcode::SUBF,
+            operator::MULF => Opcode::MULF,
+            operator::DIVF => Opcode::DIVF,
+            operator::EQF => Opcode::EQF,
+            operator::NEQF => Opcode::NEQF,
+            operator::GTF => Opcode::GTF,
+            operator::LTF => Opcode::LTF,
+            operator::GTEQF => Opcode::GTEQF,
+            operator::LTEQF => Opcode::LTEQF,
+            operator::ITOF => Opcode::ITOF,
+            operator::FTOI => Opcode::FTOI,
+            operator::JTABLE => Opcode::JTABLE,
+            operator::LOOP => Opcode::LOOP,
+            operator::PRTS => Opcode::PRTS,
+            operator::STREQ => Opcode::STREQ,
+            operator::STRCAT => Opcode::STRCAT,
+            operator::STRLEN => Opcode::STRLEN,
+            operator::ILGL => Opcode::ILGL,
             _ => Opcode::ILGL,
         }
     }
 }
 
 mod operator {
-    pub const HLT: &'static str = "hlt";
-    pub const LOAD: &'static str = "load";
-    pub const ADD: &'static str = "add";
-    pub const SUB: &'static str = "sub";
-    pub const MUL: &'static str = "mul";
-    pub const DIV: &'static str = "div";
-    pub const JMP: &'static str = "jmp";
-    pub const JMPF: &'static str = "jmpf";
-    pub const JMPB: &'static str = "jmpb";
-    pub const EQ: &'static str = "eq";
-    pub const NEQ: &'static str = "neq";
-    pub const GT: &'static str = "gt";
-    pub const LT: &'static str = "lt";
-    pub const GTEQ: &'static str = "gteq";
-    pub const LTEQ: &'static str = "lteq";
-    pub const JEQ: &'static str = "jeq";
-    pub const ILGL: &'static str = "ilgl";
+    pub const HLT: &str = "hlt";
+    pub const LOAD: &str = "load";
+    pub const ADD: &str = "add";
+    pub const SUB: &str = "sub";
+    pub const MUL: &str = "mul";
+    pub const DIV: &str = "div";
+    pub const JMP: &str = "jmp";
+    pub const JMPF: &str = "jmpf";
+    pub const JMPB: &str = "jmpb";
+    pub const EQ: &str = "eq";
+    pub const NEQ: &str = "neq";
+    pub const GT: &str = "gt";
+    pub const LT: &str = "lt";
+    pub const GTEQ: &str = "gteq";
+    pub const LTEQ: &str = "lteq";
+    pub const JEQ: &str = "jeq";
+    pub const ALLOC: &str = "alloc";
+    pub const AND: &str = "and";
+    pub const OR: &str = "or";
+    pub const XOR: &str = "xor";
+    pub const NOT: &str = "not";
+    pub const SHL: &str = "shl";
+    pub const SHR: &str = "shr";
+    pub const SAR: &str = "sar";
+    pub const ADDI: &str = "addi";
+    pub const SUBI: &str = "subi";
+    pub const MULI: &str = "muli";
+    pub const EQI: &str = "eqi";
+    pub const NEQI: &str = "neqi";
+    pub const GTI: &str = "gti";
+    pub const LTI: &str = "lti";
+    pub const GTEQI: &str = "gteqi";
+    pub const LTEQI: &str = "lteqi";
+    pub const INC: &str = "inc";
+    pub const DEC: &str = "dec";
+    pub const JNEQ: &str = "jneq";
+    pub const BRA: &str = "bra";
+    pub const BEQ: &str = "beq";
+    pub const BNEQ: &str = "bneq";
+    pub const BZ: &str = "bz";
+    pub const BNZ: &str = "bnz";
+    pub const BN: &str = "bn";
+    pub const BNN: &str = "bnn";
+    pub const BC: &str = "bc";
+    pub const BNC: &str = "bnc";
+    pub const BV: &str = "bv";
+    pub const BNV: &str = "bnv";
+    pub const DIVU: &str = "divu";
+    pub const GTU: &str = "gtu";
+    pub const LTU: &str = "ltu";
+    pub const GTEQU: &str = "gtequ";
+    pub const LTEQU: &str = "ltequ";
+    pub const REM: &str = "rem";
+    pub const MULW: &str = "mulw";
+    pub const MULWU: &str = "mulwu";
+    pub const ADC: &str = "adc";
+    pub const SBC: &str = "sbc";
+    pub const LOADF: &str = "loadf";
+    pub const ADDF: &str = "addf";
+    pub const SUBF: &str = "subf";
+    pub const MULF: &str = "mulf";
+    pub const DIVF: &str = "divf";
+    pub const EQF: &str = "eqf";
+    pub const NEQF: &str = "neqf";
+    pub const GTF: &str = "gtf";
+    pub const LTF: &str = "ltf";
+    pub const GTEQF: &str = "gteqf";
+    pub const LTEQF: &str = "lteqf";
+    pub const ITOF: &str = "itof";
+    pub const FTOI: &str = "ftoi";
+    pub const JTABLE: &str = "jtable";
+    pub const LOOP: &str = "loop";
+    pub const PRTS: &str = "prts";
+    pub const STREQ: &str = "streq";
+    pub const STRCAT: &str = "strcat";
+    pub const STRLEN: &str = "strlen";
+    pub const ILGL: &str = "ilgl";
 }
 
 mod prefix {
     pub const REGISTER: char = '$';
+    pub const FLOAT_REGISTER: char = 'f';
     pub const VALUE: char = '#';
+    pub const LABEL_USAGE: char = '@';
+    pub const LABEL: char = ':';
+    pub const DIRECTIVE: char = '.';
+    pub const QUOTE: char = '"';
+    pub const ESCAPE: char = '\\';
+    pub const COMMENT: char = ';';
 }
 
 fn is_letter(ch: char) -> bool {
     matches!(ch, 'a'..='z' | 'A'..='Z' | '_')
 }
 
+fn is_letter_or_digit(ch: char) -> bool {
+    is_letter(ch) || is_digit(ch)
+}
+
 fn is_digit(ch: char) -> bool {
-    matches!(ch, '0'..='9')
+    ch.is_ascii_digit()
 }
 
 fn is_whitespace(ch: char) -> bool {
     matches!(ch, ' ' | '\t' | '\n' | '\r')
 }
 
-struct Cursor<'a>(Chars<'a>);
+fn is_comment(ch: char) -> bool {
+    ch == prefix::COMMENT
+}
+
+// what a backslash and `ch` stand for in a string
+fn escape(ch: char) -> Option<char> {
+    match ch {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        _ => None,
+    }
+}
+
+/// The text of a string token with its escapes replaced.
+pub fn unescape(text: &str) -> String {
+    let mut chars = text.chars();
+    let mut unescaped = String::new();
+    while let Some(ch) = chars.next() {
+        unescaped.push(match ch {
+            prefix::ESCAPE => chars.next().and_then(escape).unwrap_or(ch),
+            ch => ch,
+        });
+    }
+    unescaped
+}
 
 #[derive(Debug, Clone)]
 pub struct Lexer<'a> {
@@ -88,86 +259,169 @@ pub struct Lexer<'a> {
     ch: char,
     col: usize,
     ln: usize,
+    // where the last token started
+    token_ln: usize,
+    token_col: usize,
 }
 
 // TODO: abstract lexer into a library
-impl Lexer<'_> {
-    pub fn new(input: &String) -> Lexer {
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
         let mut l = Lexer {
             input,
             cursor: input.chars(),
             pos: 0,
             next_pos: 0,
-            ln: 0,
+            ln: 1,
             col: 0,
             ch: '\0',
+            token_ln: 1,
+            token_col: 1,
         };
         l.read_char();
         l
     }
 
     fn read_char(&mut self) {
-        if self.ch == '\0' {
+        if self.ch == '\n' {
             self.col = 0;
             self.ln += 1;
         }
         self.ch = self.cursor.next().unwrap_or('\0');
         self.pos = self.next_pos;
-        self.next_pos += 1;
+        self.next_pos += self.ch.len_utf8();
         self.col += 1;
     }
 
-    fn read_while(&mut self, predicate: impl Fn(char) -> bool) -> (usize, usize, usize, usize) {
+    // consumes characters while they match, returning the span they cover
+    fn read_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
         let start = self.pos;
-        let col = self.col;
-        let ln = self.ln;
-        let mut cursor = self.cursor.clone();
-        while predicate(cursor.next().unwrap_or('\0')) {
+        while self.ch != '\0' && predicate(self.ch) {
             self.read_char()
         }
-        (start, self.next_pos, ln, col)
+        &self.input[start..self.pos]
     }
 
-    fn read_identifier(&mut self) -> Token {
-        let (start, end, _ln, _col) = self.read_while(&is_letter);
-        let span = &self.input[start..end];
-        Token::from(span)
+    // an operator, or a label when a colon follows straight away
+    fn read_identifier(&mut self) -> Token<'a> {
+        let name = self.read_while(is_letter_or_digit);
+        if self.ch == prefix::LABEL {
+            self.read_char();
+            Token::Label(name)
+        } else {
+            Token::from(name)
+        }
     }
 
-    fn read_integer(&mut self) -> Token {
-        let (start, end, _ln, _col) = self.read_while(&is_digit);
-        let literal = &self.input[start..end];
-        let num: i32 = literal.parse().unwrap_or(0);
-        Token::Integer(num)
+    fn read_label_usage(&mut self) -> Token<'a> {
+        self.read_char();
+        if !is_letter(self.ch) {
+            return Token::Operator(Opcode::ILGL);
+        }
+        Token::LabelUsage(self.read_while(is_letter_or_digit))
     }
 
-    fn read_register(&mut self) -> Token {
-        let (start, end, _ln, _col) = self.read_while(&is_digit);
-        let literal = &self.input[start..end];
-        let num: u8 = literal.parse().unwrap_or(0);
-        Token::Register(num)
+    fn read_directive(&mut self) -> Token<'a> {
+        self.read_char();
+        Token::Directive(self.read_while(is_letter_or_digit))
     }
 
-    fn skip_whitespace(&mut self) {
-        while is_whitespace(self.ch) {
+    // the text between double quotes. A string that isn't closed on its own
+    // line or has an unknown escape is illegal
+    fn read_string(&mut self) -> Token<'a> {
+        self.read_char();
+        let start = self.pos;
+        let (mut escaped, mut valid) = (false, true);
+        while !matches!(self.ch, '\n' | '\0') && (escaped || self.ch != prefix::QUOTE) {
+            valid &= !escaped || escape(self.ch).is_some();
+            escaped = !escaped && self.ch == prefix::ESCAPE;
+            self.read_char();
+        }
+        let text = &self.input[start..self.pos];
+        let closed = self.ch == prefix::QUOTE;
+        if closed {
+            self.read_char();
+        }
+        match closed && valid {
+            true => Token::String(text),
+            false => Token::Operator(Opcode::ILGL),
+        }
+    }
+
+    // an integer, or a float when a decimal point follows its digits
+    fn read_integer(&mut self) -> Token<'a> {
+        self.read_char();
+        let start = self.pos;
+        if self.ch == '-' {
+            self.read_char();
+        }
+        self.read_while(is_digit);
+        if self.ch == '.' {
+            self.read_char();
+            self.read_while(is_digit);
+            return match self.input[start..self.pos].parse() {
+                Ok(num) => Token::Float(num),
+                Err(_) => Token::Operator(Opcode::ILGL),
+            };
+        }
+        match self.input[start..self.pos].parse() {
+            Ok(num) => Token::Integer(num),
+            Err(_) => Token::Operator(Opcode::ILGL),
+        }
+    }
+
+    fn read_register(&mut self) -> Token<'a> {
+        self.read_char();
+        let float = self.ch == prefix::FLOAT_REGISTER;
+        if float {
             self.read_char();
         }
+        match self.read_while(is_digit).parse() {
+            Ok(num) if float => Token::FloatRegister(num),
+            Ok(num) => Token::Register(num),
+            Err(_) => Token::Operator(Opcode::ILGL),
+        }
     }
 
-    pub fn next_token(&mut self) -> Token {
+    fn skip_whitespace(&mut self) {
+        loop {
+            if is_whitespace(self.ch) {
+                self.read_char();
+            } else if is_comment(self.ch) {
+                self.read_while(|ch| ch != '\n');
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Line and column the last token returned by `next_token` started at.
+    pub fn position(&self) -> (usize, usize) {
+        (self.token_ln, self.token_col)
+    }
+
+    pub fn next_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
+        self.token_ln = self.ln;
+        self.token_col = self.col;
         match self.ch {
             prefix::REGISTER => self.read_register(),
             prefix::VALUE => self.read_integer(),
+            prefix::LABEL_USAGE => self.read_label_usage(),
+            prefix::DIRECTIVE => self.read_directive(),
+            prefix::QUOTE => self.read_string(),
             ch if is_letter(ch) => self.read_identifier(),
             '\0' => Token::EOF,
-            _ => Token::Operator(Opcode::ILGL),
+            _ => {
+                self.read_char();
+                Token::Operator(Opcode::ILGL)
+            }
         }
     }
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = Token;
+    type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.next_token() {
@@ -176,3 +430,121 @@ impl<'a> Iterator for Lexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let tokens: Vec<Token> = Lexer::new("load $12 #500\nADD $0 $1 $2 #-7").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Operator(Opcode::LOAD),
+                Token::Register(12),
+                Token::Integer(500),
+                Token::Operator(Opcode::ADD),
+                Token::Register(0),
+                Token::Register(1),
+                Token::Register(2),
+                Token::Integer(-7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_floats() {
+        let tokens: Vec<Token> = Lexer::new("loadf $f3 #-1.25\nitof $1 $f31 #2. #.5 $f").collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Operator(Opcode::LOADF),
+                Token::FloatRegister(3),
+                Token::Float(-1.25),
+                Token::Operator(Opcode::ITOF),
+                Token::Register(1),
+                Token::FloatRegister(31),
+                Token::Float(2.0),
+                Token::Float(0.5),
+                Token::Operator(Opcode::ILGL),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        let ops = ["lt", "gteq", "lteq", "jeq", "alloc", "xor", "sar", "nope"];
+        let source = ops.join(" ");
+        let tokens: Vec<Token> = Lexer::new(&source).collect();
+        assert_eq!(
+            tokens,
+            [
+                Opcode::LT,
+                Opcode::GTEQ,
+                Opcode::LTEQ,
+                Opcode::JEQ,
+                Opcode::ALLOC,
+                Opcode::XOR,
+                Opcode::SAR,
+                Opcode::ILGL
+            ]
+            .map(Token::Operator)
+        );
+    }
+
+    #[test]
+    fn test_positions_and_comments() {
+        let mut lexer = Lexer::new("; a comment\n  hlt ; another\n\tjmp $300");
+        assert_eq!(lexer.next_token(), Token::Operator(Opcode::HLT));
+        assert_eq!(lexer.position(), (2, 3));
+        assert_eq!(lexer.next_token(), Token::Operator(Opcode::JMP));
+        assert_eq!(lexer.position(), (3, 2));
+        assert_eq!(lexer.next_token(), Token::Operator(Opcode::ILGL));
+        assert_eq!(lexer.position(), (3, 6));
+        assert_eq!(lexer.next_token(), Token::EOF);
+    }
+
+    #[test]
+    fn test_strings() {
+        let source = r#"load $0 "hi there" "a\"b\\c\n" "" "bad\q" "open
+hlt"#;
+        let tokens: Vec<Token> = Lexer::new(source).collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Operator(Opcode::LOAD),
+                Token::Register(0),
+                Token::String("hi there"),
+                Token::String(r#"a\"b\\c\n"#),
+                Token::String(""),
+                Token::Operator(Opcode::ILGL),
+                Token::Operator(Opcode::ILGL),
+                Token::Operator(Opcode::HLT),
+            ]
+        );
+        assert_eq!(unescape(r#"a\"b\\c\n\t"#), "a\"b\\c\n\t");
+    }
+
+    #[test]
+    fn test_labels() {
+        let tokens: Vec<Token> =
+            Lexer::new("loop2: jeq @loop2\nhlt: @\n.jumptable @a @b\n.\nloop: loop").collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Label("loop2"),
+                Token::Operator(Opcode::JEQ),
+                Token::LabelUsage("loop2"),
+                Token::Label("hlt"),
+                Token::Operator(Opcode::ILGL),
+                Token::Directive("jumptable"),
+                Token::LabelUsage("a"),
+                Token::LabelUsage("b"),
+                Token::Directive(""),
+                Token::Label("loop"),
+                Token::Operator(Opcode::LOOP),
+            ]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::analysis::is_conditional;
+use crate::assembler::Program;
+use crate::instructions::{Instruction, Opcode};
+use crate::vm::{ExecutionObserver, VM};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Records which instructions ran and which way every conditional jump or
+/// branch went.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    executed: BTreeMap<usize, u64>,
+    // (taken, not taken) per conditional jump address
+    branches: BTreeMap<usize, (u64, u64)>,
+}
+
+impl ExecutionObserver for Coverage {
+    fn after_instruction(&mut self, vm: &VM, pc: usize, instruction: Instruction) {
+        *self.executed.entry(pc).or_default() += 1;
+        if is_conditional(instruction.opcode()) {
+            // not going on to the next instruction is what taking it means
+            let (taken, not_taken) = self.branches.entry(pc).or_default();
+            if vm.pc() != pc + 4 {
+                *taken += 1;
+            } else {
+                *not_taken += 1;
+            }
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// How many times the instruction at `pc` ran to completion.
+    pub fn executed(&self, pc: usize) -> u64 {
+        self.executed.get(&pc).copied().unwrap_or(0)
+    }
+
+    /// How many times the conditional jump at `pc` was taken and not taken.
+    pub fn branch(&self, pc: usize) -> (u64, u64) {
+        self.branches.get(&pc).copied().unwrap_or((0, 0))
+    }
+
+    /// Execution count per source line of `program`. Lines holding several
+    /// instructions report the most executed one.
+    pub fn lines(&self, program: &Program) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for (index, line) in program.lines.iter().enumerate() {
+            let count = lines.entry(*line).or_default();
+            *count = self.executed(index * 4).max(*count);
+        }
+        lines
+    }
+
+    /// Coverage of `program`, assembled from `source_file`, in lcov's
+    /// tracefile format.
+    pub fn lcov(&self, program: &Program, source_file: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{source_file}").unwrap();
+
+        let (mut found, mut hit) = (0, 0);
+        for (index, line) in program.lines.iter().enumerate() {
+            let pc = index * 4;
+            if !is_conditional(Opcode::from(program.code[pc])) {
+                continue;
+            }
+            let (taken, not_taken) = match self.branches.get(&pc) {
+                Some((taken, not_taken)) => (taken.to_string(), not_taken.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            writeln!(out, "BRDA:{line},{pc},0,{taken}").unwrap();
+            writeln!(out, "BRDA:{line},{pc},1,{not_taken}").unwrap();
+            let (taken, not_taken) = self.branch(pc);
+            found += 2;
+            hit += (taken > 0) as usize + (not_taken > 0) as usize;
+        }
+        writeln!(out, "BRF:{found}").unwrap();
+        writeln!(out, "BRH:{hit}").unwrap();
+
+        let lines = self.lines(program);
+        for (line, count) in &lines {
+            writeln!(out, "DA:{line},{count}").unwrap();
+        }
+        writeln!(out, "LF:{}", lines.len()).unwrap();
+        writeln!(out, "LH:{}", lines.values().filter(|count| **count > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::Stdin;
+
+    const SOURCE: &str = "load $0 #3 ; counter
+load $1 #1
+load $3 #12
+sub $0 $1 $0 ; loop
+neq $0 $2
+jeq $3
+load $4 #36
+jmp $4
+hlt ; skipped
+";
+
+    fn cover(source: &str) -> (Program, Coverage) {
+        let program = assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.stdin(program.code.clone());
+        vm.attach_observer(Coverage::new());
+        vm.run();
+        (program, vm.detach_observer().unwrap())
+    }
+
+    #[test]
+    fn test_coverage() {
+        let (program, coverage) = cover(SOURCE);
+        assert_eq!(coverage.executed(12), 3);
+        assert_eq!(coverage.executed(32), 0);
+        assert_eq!(coverage.branch(20), (2, 1));
+        assert_eq!(coverage.lines(&program).get(&4), Some(&3));
+        assert_eq!(coverage.lines(&program).get(&9), Some(&0));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (program, coverage) = cover(SOURCE);
+        assert_eq!(
+            coverage.lcov(&program, "countdown.iasm"),
+            "TN:
+SF:countdown.iasm
+BRDA:6,20,0,2
+BRDA:6,20,1,1
+BRF:2
+BRH:2
+DA:1,1
+DA:2,1
+DA:3,1
+DA:4,3
+DA:5,3
+DA:6,3
+DA:7,1
+DA:8,1
+DA:9,0
+LF:9
+LH:8
+end_of_record
+"
+        );
+    }
+
+    #[test]
+    fn test_label_branches() {
+        // jneq to a label is a BNEQ, and bz is a flag branch
+        let (program, coverage) = cover(
+            "      load $0 #2
+             top:  dec $0
+                   eqi $0 #0
+                   jneq @top
+                   bz @end
+                   hlt
+             end:  hlt",
+        );
+        assert_eq!(coverage.branch(12), (1, 1));
+        assert_eq!(coverage.branch(16), (1, 0));
+        let lcov = coverage.lcov(&program, "a.iasm");
+        assert!(lcov.contains("BRDA:4,12,0,1\nBRDA:4,12,1,1\n"));
+        assert!(lcov.contains("BRDA:5,16,0,1\nBRDA:5,16,1,0\nBRF:4\nBRH:3\n"));
+    }
+
+    #[test]
+    fn test_branches_never_reached() {
+        let (program, coverage) = cover("hlt\njeq $0\n");
+        let lcov = coverage.lcov(&program, "a.iasm");
+        assert!(lcov.contains("BRDA:2,4,0,-\nBRDA:2,4,1,-\nBRF:2\nBRH:0\n"));
+    }
+}
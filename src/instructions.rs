@@ -1,4 +1,6 @@
-#[derive(Debug, PartialEq, Clone, Copy)]
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[repr(u8)]
 pub enum Opcode {
     HLT,
@@ -18,17 +20,393 @@ pub enum Opcode {
     LTEQ,
     JEQ,
     ALLOC,
-    ILGL,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+    ADDI,
+    SUBI,
+    MULI,
+    EQI,
+    NEQI,
+    GTI,
+    LTI,
+    GTEQI,
+    LTEQI,
+    INC,
+    DEC,
+    JNEQ,
+    BRA,
+    BEQ,
+    BNEQ,
+    BZ,
+    BNZ,
+    BN,
+    BNN,
+    BC,
+    BNC,
+    BV,
+    BNV,
+    DIVU,
+    GTU,
+    LTU,
+    GTEQU,
+    LTEQU,
+    REM,
+    MULW,
+    MULWU,
+    ADC,
+    SBC,
+    LOADF,
+    ADDF,
+    SUBF,
+    MULF,
+    DIVF,
+    EQF,
+    NEQF,
+    GTF,
+    LTF,
+    GTEQF,
+    LTEQF,
+    ITOF,
+    FTOI,
+    JTABLE,
+    LOOP,
+    PRTS,
+    STREQ,
+    STRCAT,
+    STRLEN,
+    ILGL = 255,
+}
+
+/// How an instruction uses its three operand bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Layout {
+    /// no operands, all three bytes are padding
+    Nullary,
+    /// a register and a big-endian 16-bit integer
+    RegisterInteger,
+    /// a register and two bytes of padding
+    Unary,
+    /// two registers and a byte of padding
+    Comparison,
+    /// two registers and a third one for the result
+    Binary,
+    /// a register, a second one for the result and a byte of padding
+    UnaryResult,
+    /// a register, a second one for the result and a signed 8-bit integer
+    BinaryImmediate,
+    /// a register and a signed big-endian 16-bit integer
+    ComparisonImmediate,
+    /// a signed big-endian 16-bit offset from the instruction's own address,
+    /// and a byte of padding
+    Branch,
+    /// a register and a signed big-endian 16-bit offset from the instruction's
+    /// own address
+    RegisterBranch,
+}
+
+impl Layout {
+    /// How many of the operand bytes are registers. They always come first.
+    pub fn registers(self) -> usize {
+        match self {
+            Layout::Nullary | Layout::Branch => 0,
+            Layout::RegisterInteger
+            | Layout::Unary
+            | Layout::ComparisonImmediate
+            | Layout::RegisterBranch => 1,
+            Layout::Comparison | Layout::UnaryResult | Layout::BinaryImmediate => 2,
+            Layout::Binary => 3,
+        }
+    }
+
+    /// How many of the operand bytes mean something. The rest should be 0.
+    pub fn used(self) -> usize {
+        match self {
+            Layout::RegisterInteger
+            | Layout::BinaryImmediate
+            | Layout::ComparisonImmediate
+            | Layout::RegisterBranch => 3,
+            Layout::Branch => 2,
+            layout => layout.registers(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
     opcode: Opcode,
+    operands: [u8; 3],
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            operands: [0; 3],
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`. Missing bytes read as 0.
+    pub fn decode(bytes: &[u8]) -> Instruction {
+        let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+        Instruction {
+            opcode: Opcode::from(byte(0)),
+            operands: [byte(1), byte(2), byte(3)],
+        }
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    pub fn operands(&self) -> [u8; 3] {
+        self.operands
+    }
+}
+
+impl Opcode {
+    pub fn layout(&self) -> Layout {
+        match self {
+            Opcode::HLT | Opcode::ILGL => Layout::Nullary,
+            Opcode::LOAD | Opcode::LOADF | Opcode::JTABLE => Layout::RegisterInteger,
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::DIVU
+            | Opcode::MULW
+            | Opcode::MULWU
+            | Opcode::ADC
+            | Opcode::SBC
+            | Opcode::ADDF
+            | Opcode::SUBF
+            | Opcode::MULF
+            | Opcode::DIVF
+            | Opcode::STRCAT => Layout::Binary,
+            Opcode::NOT | Opcode::ITOF | Opcode::FTOI | Opcode::STRLEN => Layout::UnaryResult,
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => Layout::BinaryImmediate,
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTEQ
+            | Opcode::LTEQ
+            | Opcode::GTU
+            | Opcode::LTU
+            | Opcode::GTEQU
+            | Opcode::LTEQU
+            | Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTEQF
+            | Opcode::LTEQF
+            | Opcode::STREQ => Layout::Comparison,
+            Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::LTI
+            | Opcode::GTEQI
+            | Opcode::LTEQI => Layout::ComparisonImmediate,
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::ALLOC
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::REM
+            | Opcode::PRTS => Layout::Unary,
+            Opcode::BRA
+            | Opcode::BEQ
+            | Opcode::BNEQ
+            | Opcode::BZ
+            | Opcode::BNZ
+            | Opcode::BN
+            | Opcode::BNN
+            | Opcode::BC
+            | Opcode::BNC
+            | Opcode::BV
+            | Opcode::BNV => Layout::Branch,
+            Opcode::LOOP => Layout::RegisterBranch,
+        }
+    }
+
+    /// Whether register operand `i` names a float register rather than an
+    /// integer one. ITOF and FTOI have one of each.
+    pub fn is_float_register(&self, i: usize) -> bool {
+        match self {
+            Opcode::LOADF
+            | Opcode::ADDF
+            | Opcode::SUBF
+            | Opcode::MULF
+            | Opcode::DIVF
+            | Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::LTF
+            | Opcode::GTEQF
+            | Opcode::LTEQF => true,
+            Opcode::ITOF => i == 1,
+            Opcode::FTOI => i == 0,
+            _ => false,
+        }
+    }
+
+    /// The relative branch that goes where this jump would, which is what
+    /// the assembler uses for jumps to labels.
+    pub fn branch(&self) -> Option<Opcode> {
+        match self {
+            Opcode::JMP | Opcode::BRA => Some(Opcode::BRA),
+            Opcode::JEQ | Opcode::BEQ => Some(Opcode::BEQ),
+            Opcode::JNEQ | Opcode::BNEQ => Some(Opcode::BNEQ),
+            _ => None,
+        }
+    }
+
+    /// The form of the instruction that takes an integer instead of its
+    /// second register, if it has one. Immediate forms are their own.
+    pub fn immediate(&self) -> Option<Opcode> {
+        match self {
+            Opcode::ADD | Opcode::ADDI => Some(Opcode::ADDI),
+            Opcode::SUB | Opcode::SUBI => Some(Opcode::SUBI),
+            Opcode::MUL | Opcode::MULI => Some(Opcode::MULI),
+            Opcode::EQ | Opcode::EQI => Some(Opcode::EQI),
+            Opcode::NEQ | Opcode::NEQI => Some(Opcode::NEQI),
+            Opcode::GT | Opcode::GTI => Some(Opcode::GTI),
+            Opcode::LT | Opcode::LTI => Some(Opcode::LTI),
+            Opcode::GTEQ | Opcode::GTEQI => Some(Opcode::GTEQI),
+            Opcode::LTEQ | Opcode::LTEQI => Some(Opcode::LTEQI),
+            _ => None,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::HLT => "hlt",
+            Opcode::LOAD => "load",
+            Opcode::ADD => "add",
+            Opcode::SUB => "sub",
+            Opcode::MUL => "mul",
+            Opcode::DIV => "div",
+            Opcode::JMP => "jmp",
+            Opcode::JMPF => "jmpf",
+            Opcode::JMPB => "jmpb",
+            Opcode::EQ => "eq",
+            Opcode::NEQ => "neq",
+            Opcode::GT => "gt",
+            Opcode::LT => "lt",
+            Opcode::GTEQ => "gteq",
+            Opcode::LTEQ => "lteq",
+            Opcode::JEQ => "jeq",
+            Opcode::ALLOC => "alloc",
+            Opcode::AND => "and",
+            Opcode::OR => "or",
+            Opcode::XOR => "xor",
+            Opcode::NOT => "not",
+            Opcode::SHL => "shl",
+            Opcode::SHR => "shr",
+            Opcode::SAR => "sar",
+            Opcode::ADDI => "addi",
+            Opcode::SUBI => "subi",
+            Opcode::MULI => "muli",
+            Opcode::EQI => "eqi",
+            Opcode::NEQI => "neqi",
+            Opcode::GTI => "gti",
+            Opcode::LTI => "lti",
+            Opcode::GTEQI => "gteqi",
+            Opcode::LTEQI => "lteqi",
+            Opcode::INC => "inc",
+            Opcode::DEC => "dec",
+            Opcode::JNEQ => "jneq",
+            Opcode::BRA => "bra",
+            Opcode::BEQ => "beq",
+            Opcode::BNEQ => "bneq",
+            Opcode::BZ => "bz",
+            Opcode::BNZ => "bnz",
+            Opcode::BN => "bn",
+            Opcode::BNN => "bnn",
+            Opcode::BC => "bc",
+            Opcode::BNC => "bnc",
+            Opcode::BV => "bv",
+            Opcode::BNV => "bnv",
+            Opcode::DIVU => "divu",
+            Opcode::GTU => "gtu",
+            Opcode::LTU => "ltu",
+            Opcode::GTEQU => "gtequ",
+            Opcode::LTEQU => "ltequ",
+            Opcode::REM => "rem",
+            Opcode::MULW => "mulw",
+            Opcode::MULWU => "mulwu",
+            Opcode::ADC => "adc",
+            Opcode::SBC => "sbc",
+            Opcode::LOADF => "loadf",
+            Opcode::ADDF => "addf",
+            Opcode::SUBF => "subf",
+            Opcode::MULF => "mulf",
+            Opcode::DIVF => "divf",
+            Opcode::EQF => "eqf",
+            Opcode::NEQF => "neqf",
+            Opcode::GTF => "gtf",
+            Opcode::LTF => "ltf",
+            Opcode::GTEQF => "gteqf",
+            Opcode::LTEQF => "lteqf",
+            Opcode::ITOF => "itof",
+            Opcode::FTOI => "ftoi",
+            Opcode::JTABLE => "jtable",
+            Opcode::LOOP => "loop",
+            Opcode::PRTS => "prts",
+            Opcode::STREQ => "streq",
+            Opcode::STRCAT => "strcat",
+            Opcode::STRLEN => "strlen",
+            Opcode::ILGL => "ilgl",
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+// prints the instruction in the assembler's syntax
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c] = self.operands;
+        let [ra, rb, rc] = [0, 1, 2].map(|i| match self.opcode.is_float_register(i) {
+            true => format!("$f{}", self.operands[i]),
+            false => format!("${}", self.operands[i]),
+        });
+        match self.opcode.layout() {
+            Layout::Nullary => write!(f, "{}", self.opcode),
+            Layout::RegisterInteger => {
+                write!(f, "{} {ra} #{}", self.opcode, u16::from_be_bytes([b, c]))
+            }
+            Layout::Unary => write!(f, "{} {ra}", self.opcode),
+            Layout::Comparison => write!(f, "{} {ra} {rb}", self.opcode),
+            Layout::Binary => write!(f, "{} {ra} {rb} {rc}", self.opcode),
+            Layout::UnaryResult => write!(f, "{} {ra} {rb}", self.opcode),
+            Layout::BinaryImmediate => write!(f, "{} {ra} #{} {rb}", self.opcode, c as i8),
+            Layout::ComparisonImmediate => {
+                write!(f, "{} {ra} #{}", self.opcode, i16::from_be_bytes([b, c]))
+            }
+            Layout::Branch => write!(f, "{} #{}", self.opcode, i16::from_be_bytes([a, b])),
+            Layout::RegisterBranch => {
+                write!(f, "{} {ra} #{}", self.opcode, i16::from_be_bytes([b, c]))
+            }
+        }
     }
 }
 // ¿se puede reemplazar esto representando el programa como un vector de opcodes en lugar de un vector de bytes?
@@ -52,6 +430,65 @@ impl From<u8> for Opcode {
             14 => Opcode::LTEQ,
             15 => Opcode::JEQ,
             16 => Opcode::ALLOC,
+            17 => Opcode::AND,
+            18 => Opcode::OR,
+            19 => Opcode::XOR,
+            20 => Opcode::NOT,
+            21 => Opcode::SHL,
+            22 => Opcode::SHR,
+            23 => Opcode::SAR,
+            24 => Opcode::ADDI,
+            25 => Opcode::SUBI,
+            26 => Opcode::MULI,
+            27 => Opcode::EQI,
+            28 => Opcode::NEQI,
+            29 => Opcode::GTI,
+            30 => Opcode::LTI,
+            31 => Opcode::GTEQI,
+            32 => Opcode::LTEQI,
+            33 => Opcode::INC,
+            34 => Opcode::DEC,
+            35 => Opcode::JNEQ,
+            36 => Opcode::BRA,
+            37 => Opcode::BEQ,
+            38 => Opcode::BNEQ,
+            39 => Opcode::BZ,
+            40 => Opcode::BNZ,
+            41 => Opcode::BN,
+            42 => Opcode::BNN,
+            43 => Opcode::BC,
+            44 => Opcode::BNC,
+            45 => Opcode::BV,
+            46 => Opcode::BNV,
+            47 => Opcode::DIVU,
+            48 => Opcode::GTU,
+            49 => Opcode::LTU,
+            50 => Opcode::GTEQU,
+            51 => Opcode::LTEQU,
+            52 => Opcode::REM,
+            53 => Opcode::MULW,
+            54 => Opcode::MULWU,
+            55 => Opcode::ADC,
+            56 => Opcode::SBC,
+            57 => Opcode::LOADF,
+            58 => Opcode::ADDF,
+            59 => Opcode::SUBF,
+            60 => Opcode::MULF,
+            61 => Opcode::DIVF,
+            62 => Opcode::EQF,
+            63 => Opcode::NEQF,
+            64 => Opcode::GTF,
+            65 => Opcode::LTF,
+            66 => Opcode::GTEQF,
+            67 => Opcode::LTEQF,
+            68 => Opcode::ITOF,
+            69 => Opcode::FTOI,
+            70 => Opcode::JTABLE,
+            71 => Opcode::LOOP,
+            72 => Opcode::PRTS,
+            73 => Opcode::STREQ,
+            74 => Opcode::STRCAT,
+            75 => Opcode::STRLEN,
             _ => Opcode::ILGL,
         }
     }
@@ -71,4 +508,32 @@ mod tests {
         let i = Instruction::new(Opcode::HLT);
         assert_eq!(i.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_disassemble() {
+        let show = |bytes: &[u8]| Instruction::decode(bytes).to_string();
+        assert_eq!(show(&[1, 0, 1, 244]), "load $0 #500");
+        assert_eq!(show(&[2, 0, 1, 2]), "add $0 $1 $2");
+        assert_eq!(show(&[9, 3, 4, 0]), "eq $3 $4");
+        assert_eq!(show(&[15, 7]), "jeq $7");
+        assert_eq!(show(&[20, 1, 2, 0]), "not $1 $2");
+        assert_eq!(show(&[24, 1, 2, 253]), "addi $1 #-3 $2");
+        assert_eq!(show(&[29, 4, 255, 156]), "gti $4 #-100");
+        assert_eq!(show(&[33, 5, 0, 0]), "inc $5");
+        assert_eq!(show(&[37, 255, 248, 0]), "beq #-8");
+        assert_eq!(show(&[44, 0, 12, 0]), "bnc #12");
+        assert_eq!(show(&[48, 1, 2, 0]), "gtu $1 $2");
+        assert_eq!(show(&[52, 3, 0, 0]), "rem $3");
+        assert_eq!(show(&[57, 2, 0, 1]), "loadf $f2 #1");
+        assert_eq!(show(&[58, 0, 1, 2]), "addf $f0 $f1 $f2");
+        assert_eq!(show(&[68, 4, 5, 0]), "itof $4 $f5");
+        assert_eq!(show(&[69, 4, 5, 0]), "ftoi $f4 $5");
+        assert_eq!(show(&[70, 1, 0, 3]), "jtable $1 #3");
+        assert_eq!(show(&[71, 2, 255, 248]), "loop $2 #-8");
+        assert_eq!(show(&[72, 3, 0, 0]), "prts $3");
+        assert_eq!(show(&[74, 1, 2, 3]), "strcat $1 $2 $3");
+        assert_eq!(show(&[75, 1, 2, 0]), "strlen $1 $2");
+        assert_eq!(show(&[0, 0, 0, 0]), "hlt");
+        assert_eq!(show(&[200]), "ilgl");
+    }
 }
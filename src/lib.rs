@@ -0,0 +1,7 @@
+pub mod analysis;
+pub mod assembler;
+pub mod coverage;
+pub mod instructions;
+pub mod profiler;
+pub mod repl;
+pub mod vm;
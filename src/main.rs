@@ -1,10 +1,154 @@
-use std::io;
-pub mod assembler;
-pub mod instructions;
-pub mod repl;
-pub mod vm;
-
-fn main() -> io::Result<()> {
-    let mut r = repl::REPL::new();
-    r.run()
+use iridium::analysis::cfg::Cfg;
+use iridium::analysis::lint::lint;
+use iridium::analysis::verify::verify;
+use iridium::assembler::{assemble, Program};
+use iridium::coverage::Coverage;
+use iridium::profiler::Profiler;
+use iridium::repl;
+use iridium::vm::{Backend, Outcome, Stdin, VM};
+use std::{env, fs, io, process};
+
+const USAGE: &str = "usage: iridium                                 start the REPL
+       iridium run [--closures|--jit] <program>
+                                               run a program, optionally compiled
+       iridium profile [--annotate] <program>  run it and show where it spends its time
+       iridium coverage <source> [<lcov file>] run it and report which lines ran
+       iridium verify <program>                check a program without running it
+       iridium cfg <program>                   print its control-flow graph as DOT
+       iridium lint <program>                  warn about suspicious code
+
+<program> is either bytecode or assembly in a file ending in .iasm. Programs
+are verified before they run, and refused if anything is wrong with them.";
+
+// .iasm files are assembled, anything else is taken to be bytecode
+fn read_program(path: &str) -> io::Result<Program> {
+    let program = if path.ends_with(".iasm") {
+        assemble(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{path}:{e}")))?
+    } else {
+        Program {
+            code: fs::read(path)?,
+            ..Program::default()
+        }
+    };
+    check(path, &program.code)?;
+    Ok(program)
+}
+
+fn check(path: &str, code: &[u8]) -> io::Result<()> {
+    let findings: Vec<String> = verify(code)
+        .iter()
+        .map(|finding| format!("{path}:{finding}"))
+        .collect();
+    if findings.is_empty() {
+        Ok(())
+    } else {
+        let message = format!("{path} is not a valid program\n{}", findings.join("\n"));
+        Err(io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+}
+
+fn load(path: &str) -> io::Result<VM> {
+    let program = read_program(path)?;
+    let mut vm = VM::new();
+    vm.stdin(program.code);
+    vm.set_constants(program.constants);
+    vm.set_heap(program.data);
+    Ok(vm)
+}
+
+fn report(outcome: Outcome, vm: &VM) {
+    print!("{}", vm.output());
+    match outcome {
+        Outcome::Halted | Outcome::Finished => println!("{}", vm.stdout()),
+        Outcome::Fault(fault) => println!("fault at {}: {fault}", vm.pc()),
+        outcome => println!("stopped at {}: {:?}", vm.pc(), outcome),
+    }
+}
+
+fn run(path: &str, backend: Backend) -> io::Result<()> {
+    let mut vm = load(path)?;
+    vm.set_backend(backend);
+    let outcome = vm.run();
+    report(outcome, &vm);
+    Ok(())
+}
+
+fn profile(path: &str, annotate: bool) -> io::Result<()> {
+    let mut vm = load(path)?;
+    vm.attach_observer(Profiler::new());
+    let outcome = vm.run();
+    report(outcome, &vm);
+    let profiler = vm.detach_observer::<Profiler>().unwrap();
+    let program: Vec<u8> = vm.program().copied().collect();
+    println!("{}", profiler.report(&program));
+    if annotate {
+        print!("{}", profiler.annotate(&program));
+    }
+    Ok(())
+}
+
+fn coverage(path: &str, output: Option<&str>) -> io::Result<()> {
+    let program = read_program(path)?;
+    let mut vm = VM::new();
+    vm.stdin(program.code.clone());
+    vm.set_constants(program.constants.clone());
+    vm.set_heap(program.data.clone());
+    vm.attach_observer(Coverage::new());
+    let outcome = vm.run();
+    report(outcome, &vm);
+    let lcov = vm
+        .detach_observer::<Coverage>()
+        .unwrap()
+        .lcov(&program, path);
+    match output {
+        Some(output) => fs::write(output, lcov),
+        None => {
+            print!("{lcov}");
+            Ok(())
+        }
+    }
+}
+
+fn verify_only(path: &str) -> io::Result<()> {
+    read_program(path)?;
+    println!("{path}: ok");
+    Ok(())
+}
+
+fn lint_only(path: &str) -> io::Result<()> {
+    for found in lint(&read_program(path)?) {
+        match found.line {
+            Some(line) => println!("{path}:{line}: {}", found.warning),
+            None => println!("{path}:{found}"),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => repl::REPL::new().run(),
+        ["run", path] => run(path, Backend::Interpreter),
+        ["run", "--closures", path] => run(path, Backend::Closures),
+        ["run", "--jit", path] => run(path, Backend::Jit),
+        ["profile", path] => profile(path, false),
+        ["profile", "--annotate", path] => profile(path, true),
+        ["coverage", path] => coverage(path, None),
+        ["coverage", path, output] => coverage(path, Some(output)),
+        ["verify", path] => verify_only(path),
+        ["cfg", path] => {
+            read_program(path).map(|program| print!("{}", Cfg::new(&program.code).to_dot()))
+        }
+        ["lint", path] => lint_only(path),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(2)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("iridium: {e}");
+        process::exit(1)
+    }
 }
@@ -0,0 +1,186 @@
+use crate::analysis::is_jump;
+use crate::instructions::{Instruction, Opcode};
+use crate::vm::{ExecutionObserver, VM};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Counts executions per opcode and per address, and how often each jump
+/// went backwards, which is how loops show up in bytecode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profiler {
+    opcodes: HashMap<Opcode, u64>,
+    addresses: BTreeMap<usize, u64>,
+    back_edges: BTreeMap<(usize, usize), u64>,
+}
+
+impl ExecutionObserver for Profiler {
+    fn before_instruction(&mut self, _vm: &VM, pc: usize, instruction: Instruction) {
+        *self.opcodes.entry(instruction.opcode()).or_default() += 1;
+        *self.addresses.entry(pc).or_default() += 1;
+    }
+
+    fn after_instruction(&mut self, vm: &VM, pc: usize, instruction: Instruction) {
+        if is_jump(instruction.opcode()) && vm.pc() <= pc {
+            *self.back_edges.entry((pc, vm.pc())).or_default() += 1;
+        }
+    }
+}
+
+// most executed first, ties broken by key so reports are stable
+fn by_count<K: Ord + Copy>(counts: impl Iterator<Item = (K, u64)>) -> Vec<(K, u64)> {
+    let mut counts: Vec<_> = counts.collect();
+    counts.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.cmp(b)));
+    counts
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(&opcode).copied().unwrap_or(0)
+    }
+
+    /// How many times the instruction at `pc` ran.
+    pub fn address_count(&self, pc: usize) -> u64 {
+        self.addresses.get(&pc).copied().unwrap_or(0)
+    }
+
+    /// How many times the jump at `from` went back to `to`.
+    pub fn back_edge_count(&self, from: usize, to: usize) -> u64 {
+        self.back_edges.get(&(from, to)).copied().unwrap_or(0)
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.addresses.values().sum()
+    }
+
+    /// Opcodes, hot spots and loops, most executed first. `program` is used
+    /// to show the instruction at each hot spot.
+    pub fn report(&self, program: &[u8]) -> String {
+        let mut out = String::new();
+        let total = self.instructions().max(1);
+        writeln!(out, "{} instructions executed", self.instructions()).unwrap();
+
+        writeln!(out, "\nOpcodes:").unwrap();
+        let opcodes = self.opcodes.iter().map(|(op, n)| (*op as u8, *n));
+        for (op, count) in by_count(opcodes) {
+            let share = count as f64 * 100.0 / total as f64;
+            writeln!(out, "{count:>10} {share:>6.2}%  {}", Opcode::from(op)).unwrap();
+        }
+
+        writeln!(out, "\nHot spots:").unwrap();
+        let addresses = self.addresses.iter().map(|(pc, n)| (*pc, *n));
+        for (pc, count) in by_count(addresses) {
+            let instruction = Instruction::decode(program.get(pc..).unwrap_or_default());
+            writeln!(out, "{count:>10}  {pc:>6}  {instruction}").unwrap();
+        }
+
+        writeln!(out, "\nLoops:").unwrap();
+        let back_edges = self.back_edges.iter().map(|(edge, n)| (*edge, *n));
+        for ((from, to), count) in by_count(back_edges) {
+            writeln!(out, "{count:>10}  {from:>6} -> {to}").unwrap();
+        }
+        out
+    }
+
+    /// The program's disassembly, one instruction per line, each prefixed
+    /// with how many times it ran.
+    pub fn annotate(&self, program: &[u8]) -> String {
+        let mut out = String::new();
+        for pc in (0..program.len()).step_by(4) {
+            let count = match self.address_count(pc) {
+                0 => ".".to_string(),
+                n => n.to_string(),
+            };
+            let instruction = Instruction::decode(&program[pc..]);
+            writeln!(out, "{count:>10}  {pc:>6}  {instruction}").unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::{Outcome, Stdin};
+
+    fn profile(program: Vec<u8>) -> (VM, Profiler) {
+        let mut vm = VM::new();
+        vm.stdin(program);
+        vm.attach_observer(Profiler::new());
+        vm.run();
+        let profiler = vm.detach_observer::<Profiler>().unwrap();
+        (vm, profiler)
+    }
+
+    fn countdown() -> Vec<u8> {
+        vec![
+            1, 0, 0, 3, // load 3 to register 0
+            1, 1, 0, 1, // load 1 to register 1
+            1, 3, 0, 12, // load 12 to register 3
+            3, 0, 1, 0, // load r0 - r1 to r0
+            10, 0, 2, 0, // compare r0 and r2 for inequality
+            15, 3, 0, 0, // if not equal, jump to 12
+            0, 0, 0, 0, // halt
+        ]
+    }
+
+    #[test]
+    fn test_counts() {
+        let (vm, profiler) = profile(countdown());
+        assert_eq!(vm.registers().next(), Some(&0));
+        assert_eq!(profiler.opcode_count(Opcode::LOAD), 3);
+        assert_eq!(profiler.opcode_count(Opcode::SUB), 3);
+        assert_eq!(profiler.opcode_count(Opcode::HLT), 1);
+        assert_eq!(profiler.opcode_count(Opcode::ADD), 0);
+        assert_eq!(profiler.address_count(12), 3);
+        assert_eq!(profiler.back_edge_count(20, 12), 2);
+        assert_eq!(profiler.instructions(), 13);
+    }
+
+    #[test]
+    fn test_report() {
+        let program = countdown();
+        let (_, profiler) = profile(program.clone());
+        let report = profiler.report(&program);
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[0], "13 instructions executed");
+        assert_eq!(lines[3], "         3  23.08%  load");
+        assert_eq!(lines[10], "         3      12  sub $0 $1 $0");
+        assert!(report.contains("         2      20 -> 12"));
+        let annotated = profiler.annotate(&program);
+        assert!(annotated.contains("         3      16  neq $0 $2\n"));
+        assert!(annotated.ends_with("         1      24  hlt\n"));
+    }
+
+    #[test]
+    fn test_label_loops() {
+        // jumps to labels assemble to relative branches
+        let program = assemble(
+            "      load $0 #3
+             top:  dec $0
+                   eqi $0 #0
+                   jneq @top
+                   bnz @top
+                   hlt",
+        )
+        .unwrap()
+        .code;
+        let (_, profiler) = profile(program.clone());
+        assert_eq!(profiler.back_edge_count(12, 4), 2);
+        assert_eq!(profiler.back_edge_count(16, 4), 0);
+        assert!(profiler.report(&program).contains("         2      12 -> 4"));
+    }
+
+    #[test]
+    fn test_forward_jumps_are_not_loops() {
+        let mut vm = VM::new();
+        vm.stdin(vec![7, 0, 0, 0, 0, 0]);
+        vm.attach_observer(Profiler::new());
+        assert_eq!(vm.run(), Outcome::Halted);
+        assert_eq!(vm.observer::<Profiler>().unwrap().back_edges.len(), 0);
+    }
+}
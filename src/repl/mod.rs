@@ -1,6 +1,14 @@
-use crate::vm::{VM, Stdin};
+mod sigint;
+
+use crate::analysis::cfg::Cfg;
+use crate::analysis::lint::lint;
+use crate::analysis::verify::verify;
+use crate::assembler::Program;
+use crate::profiler::Profiler;
+use crate::vm::{Outcome, Snapshot, Stdin, Watchpoint, VM};
 use core::fmt::Debug;
 use std;
+use std::fs;
 use std::io;
 use std::io::Write;
 use std::iter::{once, Peekable};
@@ -10,6 +18,12 @@ pub struct REPL {
     vm: VM,
 }
 
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl REPL {
     pub fn new() -> REPL {
         REPL {
@@ -45,12 +59,15 @@ impl REPL {
     fn show_registers(&self) {
         println!("Listing registers and all contents:");
         self.listings(self.vm.registers());
+        println!("Float registers:");
+        self.listings(self.vm.float_registers());
+        println!("flags {}", self.vm.flags());
         println!("End of Register Listing");
     }
 
     fn parse_hex<'a>(&mut self, i: &'a str) -> Peekable<impl Iterator<Item = u8> + 'a> {
         i.split_whitespace()
-            .filter_map(|entry| u8::from_str_radix(&entry, 16).ok())
+            .filter_map(|entry| u8::from_str_radix(entry, 16).ok())
             .peekable()
     }
 
@@ -60,11 +77,133 @@ impl REPL {
             println!("Unable to decode hex string. Please enter 4 groups of 2 hex characters.");
         } else {
             self.vm.stdin(bytes);
-            self.vm.run_once();
+            let outcome = self.vm.run_once();
+            print!("{}", self.vm.take_output());
+            match outcome {
+                Some(Outcome::Halted) => println!("halt!"),
+                Some(Outcome::Fault(fault)) => println!("{fault}"),
+                _ => (),
+            }
             println!("{}", self.vm.stdout())
         }
     }
 
+    fn run_program(&mut self) {
+        let vm = &mut self.vm;
+        let outcome = sigint::interruptible(|| vm.run());
+        print!("{}", self.vm.take_output());
+        match outcome {
+            Outcome::Interrupted => println!("Interrupted at pc {}", self.vm.pc()),
+            Outcome::Breakpoint(hit) => println!("Stopped: {hit}"),
+            Outcome::Fault(fault) => println!("{fault}"),
+            Outcome::Halted => println!("halt!\n{}", self.vm.stdout()),
+            _ => println!("{}", self.vm.stdout()),
+        }
+    }
+
+    fn profile_program(&mut self) {
+        self.vm.attach_observer(Profiler::new());
+        self.run_program();
+        let profiler = self.vm.detach_observer::<Profiler>().unwrap();
+        let program: Vec<u8> = self.vm.program().copied().collect();
+        print!("{}", profiler.report(&program));
+        println!("Annotated program:");
+        print!("{}", profiler.annotate(&program));
+        println!("End of Profile");
+    }
+
+    fn verify_program(&self) {
+        let program: Vec<u8> = self.vm.program().copied().collect();
+        println!("Listing verifier findings:");
+        self.listings(verify(&program).iter().map(ToString::to_string));
+        println!("End of Findings");
+    }
+
+    fn lint_program(&self) {
+        let program = Program {
+            code: self.vm.program().copied().collect(),
+            ..Program::default()
+        };
+        println!("Listing lint warnings:");
+        self.listings(lint(&program).iter().map(ToString::to_string));
+        println!("End of Warnings");
+    }
+
+    fn show_cfg(&self) {
+        let program: Vec<u8> = self.vm.program().copied().collect();
+        print!("{}", Cfg::new(&program).to_dot());
+    }
+
+    fn save(&self, path: &str) {
+        match fs::write(path, self.vm.snapshot().to_bytes()) {
+            Ok(()) => println!("Saved VM state to {path}"),
+            Err(e) => println!("Unable to save VM state: {e}"),
+        }
+    }
+
+    fn load(&mut self, path: &str) {
+        let snapshot = fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Snapshot::from_bytes(&bytes).map_err(|e| e.to_string()));
+        match snapshot {
+            Ok(snapshot) => {
+                self.vm.restore(&snapshot);
+                println!("Restored VM state from {path}");
+            }
+            Err(e) => println!("Unable to restore VM state: {e}"),
+        }
+    }
+
+    fn set_breakpoint(&mut self, address: &str) {
+        match address.parse() {
+            Ok(address) => {
+                self.vm.add_breakpoint(address);
+                println!("Breakpoint set at {address}");
+            }
+            Err(_) => println!("Usage: :break <address>"),
+        }
+    }
+
+    // $3 watches writes to r3, $3=7 writes of 7 to r3, @12 heap byte 12, eq the eq_flag.
+    // The float registers $fN cannot be watched
+    fn parse_watchpoint(target: &str) -> Option<Watchpoint> {
+        if target == "eq" {
+            return Some(Watchpoint::EqFlag);
+        }
+        if let Some(address) = target.strip_prefix('@') {
+            return address.parse().ok().map(Watchpoint::Heap);
+        }
+        let register = target.strip_prefix('$')?;
+        match register.split_once('=') {
+            Some((register, value)) => Some(Watchpoint::RegisterValue(
+                register.parse().ok().filter(|r: &usize| *r < 32)?,
+                value.parse().ok()?,
+            )),
+            None => register
+                .parse()
+                .ok()
+                .filter(|r: &usize| *r < 32)
+                .map(Watchpoint::Register),
+        }
+    }
+
+    fn set_watchpoint(&mut self, target: &str) {
+        match Self::parse_watchpoint(target) {
+            Some(watchpoint) => {
+                self.vm.add_watchpoint(watchpoint);
+                println!("Watching {watchpoint:?}");
+            }
+            None => println!("Usage: :watch $<register>[=<value>] | @<heap address> | eq"),
+        }
+    }
+
+    fn show_breakpoints(&self) {
+        println!("Listing breakpoints and watchpoints:");
+        self.listings(self.vm.breakpoints());
+        self.listings(self.vm.watchpoints().iter());
+        println!("End of Breakpoint Listing");
+    }
+
     fn should_stop_on_command(&mut self, input: &str) -> bool {
         let mut should_stop = false;
         match input {
@@ -72,12 +211,23 @@ impl REPL {
             ":history" | ":h" => self.show_history(),
             ":program" | ":p" => self.show_program(),
             ":registers" | ":r" => self.show_registers(),
+            ":run" => self.run_program(),
+            ":profile" => self.profile_program(),
+            ":verify" => self.verify_program(),
+            ":lint" => self.lint_program(),
+            ":cfg" => self.show_cfg(),
+            ":break" | ":watch" => self.show_breakpoints(),
+            _ if input.starts_with(":break ") => self.set_breakpoint(input[7..].trim()),
+            _ if input.starts_with(":watch ") => self.set_watchpoint(input[7..].trim()),
+            _ if input.starts_with(":save ") => self.save(input[6..].trim()),
+            _ if input.starts_with(":load ") => self.load(input[6..].trim()),
             _ => self.eval(input),
         }
         should_stop
     }
 
     pub fn run(&mut self) -> io::Result<()> {
+        sigint::install(self.vm.interrupt_handle());
         let stdin = io::stdin();
         let mut buffer = String::new();
         loop {
@@ -0,0 +1,48 @@
+// Ctrl-C handling for the REPL. While a program is running, SIGINT interrupts the VM
+// instead of killing the process; at the prompt it still quits as usual.
+use crate::vm::Interrupt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+static HANDLE: OnceLock<Interrupt> = OnceLock::new();
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod sys {
+    pub const SIGINT: i32 = 2;
+
+    extern "C" {
+        pub fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        pub fn _exit(status: i32) -> !;
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_: i32) {
+    // only async-signal-safe work in here: atomics and _exit
+    match HANDLE.get() {
+        Some(handle) if RUNNING.load(Ordering::Acquire) => handle.interrupt(),
+        _ => unsafe { sys::_exit(130) },
+    }
+}
+
+/// Routes SIGINT to `handle`. Only the first call has any effect.
+pub fn install(handle: Interrupt) {
+    if HANDLE.set(handle).is_ok() {
+        #[cfg(unix)]
+        unsafe {
+            sys::signal(sys::SIGINT, on_sigint);
+        }
+    }
+}
+
+/// Runs `f` with Ctrl-C wired to the interrupt handle.
+pub fn interruptible<T>(f: impl FnOnce() -> T) -> T {
+    if let Some(handle) = HANDLE.get() {
+        handle.clear();
+    }
+    RUNNING.store(true, Ordering::Release);
+    let result = f();
+    RUNNING.store(false, Ordering::Release);
+    result
+}
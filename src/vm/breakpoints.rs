@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// A condition checked after every instruction. Only the integer registers
+/// can be watched; writes to the float registers `$fN` never trigger one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watchpoint {
+    /// any write to the register, even one that leaves its value unchanged
+    Register(usize),
+    /// a write that leaves the register holding the given value
+    RegisterValue(usize, i32),
+    /// any change to the heap byte at this address, allocating it included
+    Heap(usize),
+    /// any change to eq_flag
+    EqFlag,
+}
+
+/// Why the VM paused with `Outcome::Breakpoint`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakpointHit {
+    /// execution reached a breakpoint. The instruction there has not run yet,
+    /// and running again executes it without stopping
+    Address(usize),
+    /// the instruction at `pc` wrote a watched register
+    Register {
+        pc: usize,
+        register: usize,
+        old: i32,
+        new: i32,
+    },
+    /// the instruction at `pc` changed a watched heap byte. `None` means the
+    /// address was not allocated
+    Heap {
+        pc: usize,
+        address: usize,
+        old: Option<u8>,
+        new: Option<u8>,
+    },
+    /// the instruction at `pc` changed eq_flag
+    EqFlag { pc: usize, old: bool, new: bool },
+}
+
+impl fmt::Display for BreakpointHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let byte = |b: &Option<u8>| b.map_or("unallocated".to_string(), |b| b.to_string());
+        match self {
+            BreakpointHit::Address(pc) => write!(f, "breakpoint at {pc}"),
+            BreakpointHit::Register {
+                pc,
+                register,
+                old,
+                new,
+            } => write!(f, "${register} changed from {old} to {new} at {pc}"),
+            BreakpointHit::Heap {
+                pc,
+                address,
+                old,
+                new,
+            } => write!(
+                f,
+                "heap[{address}] changed from {} to {} at {pc}",
+                byte(old),
+                byte(new)
+            ),
+            BreakpointHit::EqFlag { pc, old, new } => {
+                write!(f, "eq_flag changed from {old} to {new} at {pc}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct Breakpoints {
+    addresses: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    // the breakpoint we last stopped at, which must not stop us again
+    resume_from: Option<usize>,
+    // address of the running instruction, and the first watchpoint it hit
+    pc: usize,
+    hit: Option<BreakpointHit>,
+}
+
+impl Breakpoints {
+    pub fn addresses(&self) -> &BTreeSet<usize> {
+        &self.addresses
+    }
+
+    pub fn addresses_mut(&mut self) -> &mut BTreeSet<usize> {
+        &mut self.addresses
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Vec<Watchpoint> {
+        &mut self.watchpoints
+    }
+
+    pub fn is_watching(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    pub fn resume_from(&mut self, pc: usize) {
+        self.resume_from = Some(pc);
+    }
+
+    /// Checked before an instruction runs.
+    pub fn check_address(&mut self, pc: usize) -> Option<BreakpointHit> {
+        if self.addresses.is_empty() || self.resume_from.take() == Some(pc) {
+            return None;
+        }
+        if self.addresses.contains(&pc) {
+            self.resume_from = Some(pc);
+            Some(BreakpointHit::Address(pc))
+        } else {
+            None
+        }
+    }
+
+    pub fn begin(&mut self, pc: usize) {
+        self.pc = pc;
+        self.hit = None;
+    }
+
+    pub fn check_write(&mut self, register: usize, old: i32, new: i32) {
+        let hit = self.watchpoints.iter().any(|watchpoint| match *watchpoint {
+            Watchpoint::Register(r) => r == register,
+            Watchpoint::RegisterValue(r, v) => r == register && v == new,
+            _ => false,
+        });
+        if hit && self.hit.is_none() {
+            self.hit = Some(BreakpointHit::Register {
+                pc: self.pc,
+                register,
+                old,
+                new,
+            });
+        }
+    }
+
+    /// Heap bytes under watch, to compare against after the instruction.
+    pub fn watched_heap(&self, heap: &[u8]) -> Vec<(usize, Option<u8>)> {
+        self.watchpoints
+            .iter()
+            .filter_map(|watchpoint| match *watchpoint {
+                Watchpoint::Heap(address) => Some((address, heap.get(address).copied())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Checked after an instruction ran; returns the first watchpoint it hit.
+    pub fn finish(
+        &mut self,
+        heap_before: Vec<(usize, Option<u8>)>,
+        heap: &[u8],
+        eq_flag: (bool, bool),
+    ) -> Option<BreakpointHit> {
+        let pc = self.pc;
+        if self.hit.is_none() {
+            self.hit = heap_before
+                .into_iter()
+                .map(|(address, old)| (address, old, heap.get(address).copied()))
+                .find(|(_, old, new)| old != new)
+                .map(|(address, old, new)| BreakpointHit::Heap {
+                    pc,
+                    address,
+                    old,
+                    new,
+                });
+        }
+        let (old, new) = eq_flag;
+        if self.hit.is_none() && old != new && self.watchpoints.contains(&Watchpoint::EqFlag) {
+            self.hit = Some(BreakpointHit::EqFlag { pc, old, new });
+        }
+        self.hit.take()
+    }
+}
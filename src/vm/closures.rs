@@ -0,0 +1,488 @@
+// Closure compilation: each basic block is turned into a list of closures
+// with their operands baked in, which then run back to back without any
+// decoding or dispatching.
+use super::execute::{remainder_unsigned, shift_left, shift_right, shift_right_arithmetic};
+use super::flags::{self, Flags};
+use super::{Fault, Outcome, VM};
+use crate::instructions::{Instruction, Layout, Opcode};
+use std::sync::Arc;
+
+type Op = Box<dyn Fn(&mut VM) -> Result<(), Fault> + Send + Sync>;
+
+pub(super) struct Block {
+    // each instruction's address and what it does
+    ops: Vec<(usize, Op)>,
+    // fuel for everything after the first instruction, which `step` pays for
+    cost: u64,
+    // fuel of the instructions after each one, given back when it faults
+    refunds: Vec<u64>,
+    // where the block falls through to
+    end: usize,
+}
+
+#[derive(Clone, Default)]
+pub(super) enum Compiled {
+    #[default]
+    Pending,
+    // nothing here can be compiled, so the interpreter takes this one
+    Interpret,
+    Block(Arc<Block>),
+}
+
+fn set(vm: &mut VM, register: usize, v: i32) {
+    vm.registers[register] = v;
+    vm.stdout = register;
+}
+
+fn arithmetic(x: usize, y: usize, output: usize, f: fn(i32, i32) -> i32) -> Op {
+    Box::new(move |vm| {
+        set(vm, output, f(vm.registers[x], vm.registers[y]));
+        Ok(())
+    })
+}
+
+// arithmetic that sets the flags
+fn flagged(x: usize, y: usize, output: usize, f: fn(i32, i32) -> (i32, Flags)) -> Op {
+    Box::new(move |vm| {
+        let (v, flags) = f(vm.registers[x], vm.registers[y]);
+        set(vm, output, v);
+        vm.flags = flags;
+        Ok(())
+    })
+}
+
+fn division(
+    x: usize,
+    y: usize,
+    output: usize,
+    f: fn(i32, i32) -> (i32, Flags),
+    remainder: fn(i32, i32) -> i32,
+) -> Op {
+    Box::new(move |vm| {
+        let (x, y) = (vm.registers[x], vm.registers[y]);
+        if y == 0 {
+            return Err(Fault::DivisionByZero);
+        }
+        let (v, flags) = f(x, y);
+        set(vm, output, v);
+        vm.flags = flags;
+        vm.remainder = remainder(x, y);
+        Ok(())
+    })
+}
+
+// the low word goes to the output register, the high one to the next
+fn wide(x: usize, y: usize, output: usize, f: fn(i32, i32) -> ((i32, i32), Flags)) -> Op {
+    Box::new(move |vm| {
+        let ((low, high), flags) = f(vm.registers[x], vm.registers[y]);
+        set(vm, output + 1, high);
+        set(vm, output, low);
+        vm.flags = flags;
+        Ok(())
+    })
+}
+
+fn with_carry(x: usize, y: usize, output: usize, f: fn(i32, i32, bool) -> (i32, Flags)) -> Op {
+    Box::new(move |vm| {
+        let (v, flags) = f(vm.registers[x], vm.registers[y], vm.flags.carry);
+        set(vm, output, v);
+        vm.flags = flags;
+        Ok(())
+    })
+}
+
+fn comparison(x: usize, y: usize, f: fn(i32, i32) -> bool) -> Op {
+    Box::new(move |vm| {
+        let (x, y) = (vm.registers[x], vm.registers[y]);
+        vm.eq_flag = f(x, y);
+        vm.flags = flags::compare(x, y);
+        Ok(())
+    })
+}
+
+fn immediate(x: usize, y: i32, output: usize, f: fn(i32, i32) -> (i32, Flags)) -> Op {
+    Box::new(move |vm| {
+        let (v, flags) = f(vm.registers[x], y);
+        set(vm, output, v);
+        vm.flags = flags;
+        Ok(())
+    })
+}
+
+fn float_arithmetic(x: usize, y: usize, output: usize, f: fn(f64, f64) -> f64) -> Op {
+    Box::new(move |vm| {
+        vm.float_registers[output] = f(vm.float_registers[x], vm.float_registers[y]);
+        Ok(())
+    })
+}
+
+fn float_comparison(x: usize, y: usize, f: fn(f64, f64) -> bool) -> Op {
+    Box::new(move |vm| {
+        vm.eq_flag = f(vm.float_registers[x], vm.float_registers[y]);
+        Ok(())
+    })
+}
+
+fn comparison_immediate(x: usize, [_, high, low]: [u8; 3], f: fn(i32, i32) -> bool) -> Op {
+    let y = i16::from_be_bytes([high, low]) as i32;
+    Box::new(move |vm| {
+        let x = vm.registers[x];
+        vm.eq_flag = f(x, y);
+        vm.flags = flags::compare(x, y);
+        Ok(())
+    })
+}
+
+// Returns the instruction's closure and whether it ends the block. Anything
+// that can fault before running (HLT, ILGL, bad registers) is left to the
+// interpreter, so it stops the block before it.
+fn compile(pc: usize, instruction: Instruction) -> Option<(Op, bool)> {
+    let operands = instruction.operands();
+    let [a, b, c] = operands.map(|r| r as usize);
+    let valid = |registers: &[usize]| registers.iter().all(|&r| r < 32);
+    let op: Op = match instruction.opcode() {
+        Opcode::LOAD if valid(&[a]) => {
+            let v = u16::from_be_bytes([operands[1], operands[2]]) as i32;
+            Box::new(move |vm| {
+                set(vm, a, v);
+                Ok(())
+            })
+        }
+        Opcode::ADD if valid(&[a, b, c]) => flagged(a, b, c, flags::add),
+        Opcode::SUB if valid(&[a, b, c]) => flagged(a, b, c, flags::sub),
+        Opcode::MUL if valid(&[a, b, c]) => flagged(a, b, c, flags::mul),
+        Opcode::DIV if valid(&[a, b, c]) => division(a, b, c, flags::div, i32::wrapping_rem),
+        Opcode::DIVU if valid(&[a, b, c]) => {
+            division(a, b, c, flags::div_unsigned, remainder_unsigned)
+        }
+        Opcode::REM if valid(&[a]) => Box::new(move |vm| {
+            set(vm, a, vm.remainder);
+            Ok(())
+        }),
+        Opcode::MULW if valid(&[a, b, c, c + 1]) => wide(a, b, c, flags::mul_wide),
+        Opcode::MULWU if valid(&[a, b, c, c + 1]) => wide(a, b, c, flags::mul_wide_unsigned),
+        Opcode::ADC if valid(&[a, b, c]) => with_carry(a, b, c, flags::add_with_carry),
+        Opcode::SBC if valid(&[a, b, c]) => with_carry(a, b, c, flags::sub_with_borrow),
+        Opcode::AND if valid(&[a, b, c]) => arithmetic(a, b, c, |x, y| x & y),
+        Opcode::OR if valid(&[a, b, c]) => arithmetic(a, b, c, |x, y| x | y),
+        Opcode::XOR if valid(&[a, b, c]) => arithmetic(a, b, c, |x, y| x ^ y),
+        Opcode::SHL if valid(&[a, b, c]) => arithmetic(a, b, c, shift_left),
+        Opcode::SHR if valid(&[a, b, c]) => arithmetic(a, b, c, shift_right),
+        Opcode::SAR if valid(&[a, b, c]) => arithmetic(a, b, c, shift_right_arithmetic),
+        Opcode::NOT if valid(&[a, b]) => Box::new(move |vm| {
+            set(vm, b, !vm.registers[a]);
+            Ok(())
+        }),
+        Opcode::ADDI if valid(&[a, b]) => immediate(a, operands[2] as i8 as i32, b, flags::add),
+        Opcode::SUBI if valid(&[a, b]) => immediate(a, operands[2] as i8 as i32, b, flags::sub),
+        Opcode::MULI if valid(&[a, b]) => immediate(a, operands[2] as i8 as i32, b, flags::mul),
+        Opcode::INC if valid(&[a]) => immediate(a, 1, a, flags::add),
+        Opcode::DEC if valid(&[a]) => immediate(a, 1, a, flags::sub),
+        Opcode::EQ if valid(&[a, b]) => comparison(a, b, |x, y| x == y),
+        Opcode::NEQ if valid(&[a, b]) => comparison(a, b, |x, y| x != y),
+        Opcode::GT if valid(&[a, b]) => comparison(a, b, |x, y| x > y),
+        Opcode::LT if valid(&[a, b]) => comparison(a, b, |x, y| x < y),
+        Opcode::GTEQ if valid(&[a, b]) => comparison(a, b, |x, y| x >= y),
+        Opcode::LTEQ if valid(&[a, b]) => comparison(a, b, |x, y| x <= y),
+        Opcode::GTU if valid(&[a, b]) => comparison(a, b, |x, y| (x as u32) > (y as u32)),
+        Opcode::LTU if valid(&[a, b]) => comparison(a, b, |x, y| (x as u32) < (y as u32)),
+        Opcode::GTEQU if valid(&[a, b]) => comparison(a, b, |x, y| (x as u32) >= (y as u32)),
+        Opcode::LTEQU if valid(&[a, b]) => comparison(a, b, |x, y| (x as u32) <= (y as u32)),
+        Opcode::EQI if valid(&[a]) => comparison_immediate(a, operands, |x, y| x == y),
+        Opcode::NEQI if valid(&[a]) => comparison_immediate(a, operands, |x, y| x != y),
+        Opcode::GTI if valid(&[a]) => comparison_immediate(a, operands, |x, y| x > y),
+        Opcode::LTI if valid(&[a]) => comparison_immediate(a, operands, |x, y| x < y),
+        Opcode::GTEQI if valid(&[a]) => comparison_immediate(a, operands, |x, y| x >= y),
+        Opcode::LTEQI if valid(&[a]) => comparison_immediate(a, operands, |x, y| x <= y),
+        Opcode::LOADF if valid(&[a]) => {
+            let index = u16::from_be_bytes([operands[1], operands[2]]);
+            Box::new(move |vm| {
+                let v = *vm
+                    .constants
+                    .get(index as usize)
+                    .ok_or(Fault::InvalidConstant(index))?;
+                vm.float_registers[a] = v;
+                Ok(())
+            })
+        }
+        Opcode::ADDF if valid(&[a, b, c]) => float_arithmetic(a, b, c, |x, y| x + y),
+        Opcode::SUBF if valid(&[a, b, c]) => float_arithmetic(a, b, c, |x, y| x - y),
+        Opcode::MULF if valid(&[a, b, c]) => float_arithmetic(a, b, c, |x, y| x * y),
+        Opcode::DIVF if valid(&[a, b, c]) => float_arithmetic(a, b, c, |x, y| x / y),
+        Opcode::EQF if valid(&[a, b]) => float_comparison(a, b, |x, y| x == y),
+        Opcode::NEQF if valid(&[a, b]) => float_comparison(a, b, |x, y| x != y),
+        Opcode::GTF if valid(&[a, b]) => float_comparison(a, b, |x, y| x > y),
+        Opcode::LTF if valid(&[a, b]) => float_comparison(a, b, |x, y| x < y),
+        Opcode::GTEQF if valid(&[a, b]) => float_comparison(a, b, |x, y| x >= y),
+        Opcode::LTEQF if valid(&[a, b]) => float_comparison(a, b, |x, y| x <= y),
+        Opcode::ITOF if valid(&[a, b]) => Box::new(move |vm| {
+            vm.float_registers[b] = vm.registers[a] as f64;
+            Ok(())
+        }),
+        Opcode::FTOI if valid(&[a, b]) => Box::new(move |vm| {
+            set(vm, b, vm.float_registers[a] as i32);
+            Ok(())
+        }),
+        Opcode::ALLOC if valid(&[a]) => Box::new(move |vm| vm.alloc(operands)),
+        Opcode::PRTS if valid(&[a]) => Box::new(move |vm| vm.print_string(operands)),
+        Opcode::STREQ if valid(&[a, b]) => Box::new(move |vm| vm.compare_strings(operands)),
+        Opcode::STRCAT if valid(&[a, b, c]) => Box::new(move |vm| vm.concat_strings(operands)),
+        Opcode::STRLEN if valid(&[a, b]) => Box::new(move |vm| vm.string_length(operands)),
+        Opcode::JMP if valid(&[a]) => return Some((Box::new(move |vm| vm.jump(operands)), true)),
+        Opcode::JMPF if valid(&[a]) => {
+            return Some((Box::new(move |vm| vm.jump_forward(pc, operands)), true))
+        }
+        Opcode::JMPB if valid(&[a]) => {
+            return Some((Box::new(move |vm| vm.jump_back(pc, operands)), true))
+        }
+        Opcode::JEQ if valid(&[a]) => return Some((Box::new(move |vm| vm.jeq(operands)), true)),
+        Opcode::JNEQ if valid(&[a]) => {
+            return Some((Box::new(move |vm| vm.jneq(operands)), true))
+        }
+        Opcode::JTABLE if valid(&[a]) => {
+            return Some((Box::new(move |vm| vm.jump_table(pc, operands)), true))
+        }
+        Opcode::LOOP if valid(&[a]) => {
+            return Some((Box::new(move |vm| vm.count_down(pc, operands)), true))
+        }
+        opcode if opcode.layout() == Layout::Branch => {
+            return Some((
+                Box::new(move |vm| vm.branch(pc, operands, vm.condition(opcode))),
+                true,
+            ))
+        }
+        _ => return None,
+    };
+    Some((op, false))
+}
+
+/// Compiles the basic block starting at `slot`, if its first instruction
+/// can be compiled at all.
+fn compile_block(decoded: &[Instruction], costs: &[u64; 256], slot: usize) -> Option<Block> {
+    let mut ops = vec![];
+    let mut opcodes = vec![];
+    for (pc, instruction) in decoded.iter().enumerate().skip(slot).map(|(s, i)| (s * 4, *i)) {
+        let Some((op, ends_block)) = compile(pc, instruction) else {
+            break;
+        };
+        ops.push((pc, op));
+        opcodes.push(instruction.opcode());
+        if ends_block {
+            break;
+        }
+    }
+    if ops.is_empty() {
+        return None;
+    }
+    let mut refunds: Vec<u64> = opcodes
+        .iter()
+        .rev()
+        .scan(0, |after, opcode| {
+            let refund = *after;
+            *after += costs[*opcode as usize];
+            Some(refund)
+        })
+        .collect();
+    refunds.reverse();
+    Some(Block {
+        cost: refunds[0],
+        end: (slot + ops.len()) * 4,
+        ops,
+        refunds,
+    })
+}
+
+/// Compiles the whole program as consecutive basic blocks. Jumps into the
+/// middle of one compile another block starting there when first taken.
+pub(super) fn compile_program(decoded: &[Instruction], costs: &[u64; 256]) -> Vec<Compiled> {
+    let mut compiled = vec![Compiled::Pending; decoded.len()];
+    let mut slot = 0;
+    while slot < decoded.len() {
+        match compile_block(decoded, costs, slot) {
+            Some(block) => {
+                let len = block.ops.len();
+                compiled[slot] = Compiled::Block(Arc::new(block));
+                slot += len;
+            }
+            None => {
+                compiled[slot] = Compiled::Interpret;
+                slot += 1;
+            }
+        }
+    }
+    compiled
+}
+
+impl VM {
+    fn compiled_block(&mut self, pc: usize) -> Option<Arc<Block>> {
+        if !pc.is_multiple_of(4) {
+            return None;
+        }
+        let slot = pc / 4;
+        if let Compiled::Pending = self.compiled.get(slot)? {
+            self.compiled[slot] = match compile_block(&self.decoded, &self.costs, slot) {
+                Some(block) => Compiled::Block(Arc::new(block)),
+                None => Compiled::Interpret,
+            };
+        }
+        match &self.compiled[slot] {
+            Compiled::Block(block) => Some(block.clone()),
+            _ => None,
+        }
+    }
+
+    /// Runs the compiled block at the pc in one go. Falls back to
+    /// interpreting a single instruction when there is no block, or when
+    /// something would stop the VM partway through it (a breakpoint or
+    /// running out of fuel). Interrupts are only noticed between blocks.
+    pub(super) fn execute_compiled(&mut self) -> Option<Outcome> {
+        let pc = self.pc;
+        let block = match self.compiled_block(pc) {
+            Some(block)
+                if self.fuel.is_none_or(|fuel| fuel >= block.cost)
+                    && self
+                        .breakpoints
+                        .addresses()
+                        .range(pc + 1..block.end)
+                        .next()
+                        .is_none() =>
+            {
+                block
+            }
+            _ => return self.execute(pc, self.fetch(pc)),
+        };
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= block.cost;
+        }
+        self.pc = block.end;
+        for (i, (pc, op)) in block.ops.iter().enumerate() {
+            if let Err(fault) = op(self) {
+                if let Some(fuel) = &mut self.fuel {
+                    *fuel += block.refunds[i];
+                }
+                return self.continue_after(*pc, Err(fault));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::differential::{assert_same, random_programs};
+    use crate::vm::{Backend, Stdin};
+
+    fn closures(vm: &mut VM) {
+        vm.set_backend(Backend::Closures);
+    }
+
+    #[test]
+    fn test_compile_program() {
+        let code = assemble("load $0 #1\nadd $0 $0 $1\njeq $1\nsub $0 $0 $0\nhlt\nmul $0 $0 $40")
+            .unwrap()
+            .code;
+        let decoded: Vec<Instruction> = code.chunks_exact(4).map(Instruction::decode).collect();
+        let compiled = compile_program(&decoded, &[1; 256]);
+        let ops = |slot: usize| match &compiled[slot] {
+            Compiled::Block(block) => Some((block.ops.len(), block.end, block.cost)),
+            _ => None,
+        };
+        assert_eq!(ops(0), Some((3, 12, 2)));
+        assert_eq!(ops(3), Some((1, 16, 0)));
+        assert!(matches!(compiled[4], Compiled::Interpret));
+        assert!(matches!(compiled[5], Compiled::Interpret));
+        assert!(matches!(compiled[1], Compiled::Pending));
+    }
+
+    #[test]
+    fn test_closures_loop() {
+        let code = assemble(
+            "load $0 #50
+             load $1 #1
+             load $2 #0
+             load $3 #12
+             load $5 #3
+             add $4 $5 $4
+             sub $0 $1 $0
+             gt $0 $2
+             jeq $3",
+        )
+        .unwrap()
+        .code;
+        let mut vm = VM::new();
+        closures(&mut vm);
+        vm.stdin(code.iter().copied());
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[4], 150);
+        // the loop jumps into the middle of the first block
+        assert!(matches!(vm.compiled[3], Compiled::Block(_)));
+        for fuel in 0..40 {
+            assert_same(&code, closures, |vm| vm.set_fuel(Some(fuel)));
+        }
+    }
+
+    #[test]
+    fn test_closures_edge_cases() {
+        let none = |_: &mut VM| ();
+        let code = [1, 0, 0, 3, 1, 1, 0, 0, 5, 0, 1, 2, 2, 0, 0, 3];
+        // division by zero halfway through a block gives back the rest's fuel
+        assert_same(&code, closures, |vm| vm.set_fuel(Some(10)));
+        let mut vm = VM::new();
+        closures(&mut vm);
+        vm.stdin(code);
+        vm.set_fuel(Some(10));
+        assert_eq!(vm.run(), Outcome::Fault(Fault::DivisionByZero));
+        assert_eq!((vm.pc(), vm.fuel()), (8, Some(7)));
+        // breakpoints inside a block
+        assert_same(&code, closures, |vm| vm.add_breakpoint(4));
+        assert_same(&code, closures, |vm| vm.add_breakpoint(12));
+        // illegal opcodes, bad registers and truncated code are interpreted
+        assert_same(&[1, 0, 0, 1, 200, 0, 0, 0], closures, none);
+        assert_same(&[1, 0, 0, 1, 2, 0, 0, 40], closures, none);
+        assert_same(&[1, 0, 0, 1, 2, 0], closures, none);
+        assert_same(&[1, 0, 0, 1, 0, 0, 0, 0, 1, 1, 0, 1], closures, none);
+        assert_same(&[1, 0, 0, 1, 53, 0, 0, 31], closures, none);
+        // floats, with and without the constants LOADF needs
+        let floats =
+            assemble("itof $0 $f1\nloadf $f0 #2.5\naddf $f0 $f0 $f1\nftoi $f1 $2").unwrap();
+        assert_same(&floats.code, closures, |vm| {
+            vm.set_constants(floats.constants.clone())
+        });
+        assert_same(&floats.code, closures, none);
+        // shift counts past 31 wrap around
+        let shifts = assemble("load $0 #40\nnot $0 $1\nshl $1 $0 $2\nshr $1 $0 $3\nsar $1 $0 $4")
+            .unwrap()
+            .code;
+        assert_same(&shifts, closures, none);
+        // costs are baked into the blocks
+        assert_same(&code, closures, |vm| {
+            vm.set_fuel(Some(9));
+            vm.set_cost(Opcode::LOAD, 4);
+        });
+    }
+
+    #[test]
+    fn test_closures_recompile() {
+        let mut vm = VM::new();
+        closures(&mut vm);
+        vm.stdin([1, 0, 0, 2]);
+        vm.run();
+        vm.stdin([2, 0, 0, 1]);
+        vm.pc = 0;
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[1], 4);
+        vm.pc = 0;
+        vm.set_cost(Opcode::ADD, 5);
+        assert_eq!(vm.run_with_budget(3), Outcome::OutOfFuel);
+        assert_eq!(vm.pc(), 4);
+    }
+
+    #[test]
+    fn test_closures_differential() {
+        for code in random_programs(0x9e37_79b9, 500) {
+            assert_same(&code, closures, |vm| vm.set_fuel(Some(40)));
+        }
+    }
+}
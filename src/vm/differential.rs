@@ -0,0 +1,96 @@
+// Helpers for checking that the faster ways of running a program behave
+// exactly like the plain interpreter.
+use super::{Outcome, Stdin, VM};
+
+fn run(code: &[u8], configure: impl Fn(&mut VM), setup: impl Fn(&mut VM)) -> (Outcome, VM) {
+    let mut vm = VM::new();
+    configure(&mut vm);
+    vm.stdin(code.iter().copied());
+    setup(&mut vm);
+    (vm.run(), vm)
+}
+
+/// Runs `code` on a VM set up by `configure` and on a plain one, and checks
+/// they end up in the same state. `setup` is applied to both.
+pub(super) fn assert_same(code: &[u8], configure: impl Fn(&mut VM), setup: impl Fn(&mut VM)) {
+    let plain = |vm: &mut VM| {
+        vm.set_predecoding(false);
+        vm.set_fusion(false);
+    };
+    let (outcome, vm) = run(code, configure, &setup);
+    let (expected, plain) = run(code, plain, &setup);
+    assert_eq!(outcome, expected, "{code:?}");
+    assert_eq!(vm.snapshot(), plain.snapshot(), "{code:?}");
+    assert_eq!(vm.fuel(), plain.fuel(), "{code:?}");
+    assert_eq!(vm.output(), plain.output(), "{code:?}");
+}
+
+/// Like `assert_same`, but going one `run_once` at a time and comparing the
+/// two VMs after every step, for at most `steps` steps.
+pub(super) fn assert_same_steps(
+    code: &[u8],
+    configure: impl Fn(&mut VM),
+    setup: impl Fn(&mut VM),
+    steps: usize,
+) {
+    let new = |configure: &dyn Fn(&mut VM)| {
+        let mut vm = VM::new();
+        configure(&mut vm);
+        vm.stdin(code.iter().copied());
+        setup(&mut vm);
+        vm
+    };
+    let mut vm = new(&configure);
+    let mut plain = new(&|vm: &mut VM| {
+        vm.set_predecoding(false);
+        vm.set_fusion(false);
+    });
+    for step in 0..steps {
+        let outcome = vm.run_once();
+        assert_eq!(outcome, plain.run_once(), "{code:?} step {step}");
+        assert_eq!(vm.snapshot(), plain.snapshot(), "{code:?} step {step}");
+        if outcome.is_some() {
+            return;
+        }
+    }
+}
+
+/// Random programs over a handful of registers, mixing arithmetic, bitwise
+/// operations, comparisons and jumps. Give them little fuel: nothing stops
+/// them from looping. DIVF is left out, as 0 / 0 gives NaNs, which never
+/// compare equal.
+pub(super) fn random_programs(mut seed: u32, count: usize) -> impl Iterator<Item = Vec<u8>> {
+    let mut next = move |n: u32| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed % n) as u8
+    };
+    let opcodes = [
+        1, 1, 2, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 15, 17, 18, 19, 20, 21, 22, 23,
+        24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46,
+        47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 62, 63, 64, 65, 66, 67, 68, 68, 69,
+        70, 71, 72, 73, 74, 75,
+    ];
+    (0..count).map(move |_| {
+        let len = 4 + next(20) as usize;
+        (0..len)
+            .flat_map(|_| {
+                let op = opcodes[next(opcodes.len() as u32) as usize];
+                match op {
+                    1 => [op, next(6), 0, next(len as u32 * 4)],
+                    70 => [op, next(6), 0, next(4)],
+                    // branches mostly land on instructions in the program
+                    36..=46 | 71 => {
+                        let [high, low] = ((next(len as u32) as i16 - 4) * 4).to_be_bytes();
+                        match op {
+                            71 => [op, next(6), high, low],
+                            _ => [op, high, low, 0],
+                        }
+                    }
+                    _ => [op, next(6), next(6), next(6)],
+                }
+            })
+            .collect()
+    })
+}
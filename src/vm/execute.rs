@@ -0,0 +1,614 @@
+// Decoding and executing instructions.
+use super::flags::{self, Flags};
+use super::fusion::{self, Superinstruction};
+use super::closures;
+use super::{Backend, Fault, Outcome, VM};
+use crate::instructions::{Instruction, Opcode};
+
+// Shifts only look at the low 5 bits of the count, like x86 does, so
+// shifting by 32 leaves the value alone.
+pub(super) fn shift_left(x: i32, count: i32) -> i32 {
+    x.wrapping_shl(count as u32)
+}
+
+pub(super) fn shift_right(x: i32, count: i32) -> i32 {
+    (x as u32).wrapping_shr(count as u32) as i32
+}
+
+pub(super) fn shift_right_arithmetic(x: i32, count: i32) -> i32 {
+    x.wrapping_shr(count as u32)
+}
+
+// Strings are UTF-8 and end at the first NUL byte after `address`.
+fn heap_string(heap: &[u8], address: i32) -> Result<&str, Fault> {
+    let start = usize::try_from(address)
+        .ok()
+        .filter(|&start| start < heap.len())
+        .ok_or(Fault::InvalidAddress(address))?;
+    let len = heap[start..]
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(Fault::UnterminatedString(start))?;
+    std::str::from_utf8(&heap[start..start + len])
+        .map_err(|e| Fault::InvalidUtf8(start + e.valid_up_to()))
+}
+
+// DIVU's remainder, which keeps its bits when it doesn't fit in an i32
+pub(super) fn remainder_unsigned(x: i32, y: i32) -> i32 {
+    ((x as u32) % (y as u32)) as i32
+}
+
+impl VM {
+    // Keeps the decoded copy of the program in step with the bytes. Code is
+    // only ever appended, so normally just the new instructions get decoded.
+    pub(super) fn refresh_decoded(&mut self) {
+        let slots = self.program.len() / 4;
+        if self.decoded.len() != slots {
+            self.discard_compiled();
+        }
+        if self.decoded.len() > slots {
+            self.decoded.clear();
+        }
+        let start = self.decoded.len();
+        let new = self.program[start * 4..slots * 4]
+            .chunks_exact(4)
+            .map(Instruction::decode);
+        self.decoded.extend(new);
+        // the old last instruction may pair up with the first new one
+        fusion::fuse_from(&self.decoded, &mut self.fused, start.saturating_sub(1));
+        match self.backend {
+            Backend::Closures if self.compiled.is_empty() => {
+                self.compiled = closures::compile_program(&self.decoded, &self.costs);
+            }
+            Backend::Jit if self.jit.is_empty() => self.jit.reset(slots),
+            _ => (),
+        }
+    }
+
+    // compiled code has the program and instruction costs baked in
+    pub(super) fn discard_compiled(&mut self) {
+        self.compiled.clear();
+        self.jit.clear();
+    }
+
+    // Aligned instructions come from the decoded copy, anything else (say,
+    // after a JMPF to an odd offset) is decoded on the spot.
+    pub(super) fn fetch(&self, pc: usize) -> Instruction {
+        if self.use_decoded && pc.is_multiple_of(4) {
+            if let Some(instruction) = self.decoded.get(pc / 4) {
+                return *instruction;
+            }
+        }
+        Instruction::decode(&self.program[pc..])
+    }
+
+    /// Executes the instruction at the pc, or the whole block it starts when
+    /// running compiled.
+    pub(super) fn execute_next(&mut self) -> Option<Outcome> {
+        match self.backend {
+            Backend::Interpreter => self.interpret_next(),
+            Backend::Closures => self.execute_compiled(),
+            Backend::Jit => self.execute_jit(),
+        }
+    }
+
+    /// Executes the instruction at the pc, fused with the one after it when
+    /// they form a superinstruction.
+    pub(super) fn interpret_next(&mut self) -> Option<Outcome> {
+        let pc = self.pc;
+        if self.use_fusion && self.use_decoded && pc.is_multiple_of(4) {
+            if let Some(Some(fused)) = self.fused.get(pc / 4) {
+                return self.execute_fused(pc, *fused);
+            }
+        }
+        self.execute(pc, self.fetch(pc))
+    }
+
+    // Runs both halves as if stepped one at a time. Anything that would stop
+    // the VM in between (a breakpoint, an interrupt, running out of fuel)
+    // ends the superinstruction after its first half, so the next step can
+    // report it.
+    fn execute_fused(&mut self, pc: usize, fused: Superinstruction) -> Option<Outcome> {
+        self.pc = pc + 4;
+        let first = match fused {
+            Superinstruction::CompareJump {
+                compare, operands, ..
+            } => self.compare(operands, compare),
+            Superinstruction::LoadAdd { load, .. } => self.load(load),
+        };
+        if first.is_err() {
+            return self.continue_after(pc, first);
+        }
+        let second = self.pc;
+        if self.interrupt.is_pending()
+            || self.breakpoints.addresses().contains(&second)
+            || !self.burn_fuel()
+        {
+            return None;
+        }
+        self.pc = second + 4;
+        let result = match fused {
+            Superinstruction::CompareJump { target, .. } => self.jeq(target),
+            Superinstruction::LoadAdd { add, .. } => self.add(add),
+        };
+        self.continue_after(second, result)
+    }
+
+    fn register(&self, register: u8) -> Result<usize, Fault> {
+        if (register as usize) < self.registers.len() {
+            Ok(register as usize)
+        } else {
+            Err(Fault::InvalidRegister(register))
+        }
+    }
+
+    pub(super) fn set_register(&mut self, register: usize, v: i32) {
+        if let Some(history) = &mut self.history {
+            history.record_register(register, self.registers[register]);
+        }
+        if self.breakpoints.is_watching() {
+            self.breakpoints
+                .check_write(register, self.registers[register], v);
+        }
+        self.registers[register] = v;
+        self.stdout = register;
+        if let Some(trace) = &mut self.trace {
+            trace.record_write(register, v);
+        }
+    }
+
+    fn read(&self, register: u8) -> Result<i32, Fault> {
+        Ok(self.registers[self.register(register)?])
+    }
+
+    fn read_two(&self, x: u8, y: u8) -> Result<(i32, i32), Fault> {
+        Ok((self.read(x)?, self.read(y)?))
+    }
+
+    fn write(&mut self, register: u8, v: i32) -> Result<(), Fault> {
+        let register = self.register(register)?;
+        self.set_register(register, v);
+        Ok(())
+    }
+
+    fn float_register(&self, register: u8) -> Result<usize, Fault> {
+        if (register as usize) < self.float_registers.len() {
+            Ok(register as usize)
+        } else {
+            Err(Fault::InvalidRegister(register))
+        }
+    }
+
+    fn set_float_register(&mut self, register: usize, v: f64) {
+        if let Some(history) = &mut self.history {
+            history.record_float_register(register, self.float_registers[register]);
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.record_float_write(register, v);
+        }
+        self.float_registers[register] = v;
+    }
+
+    fn read_float(&self, register: u8) -> Result<f64, Fault> {
+        Ok(self.float_registers[self.float_register(register)?])
+    }
+
+    fn write_float(&mut self, register: u8, v: f64) -> Result<(), Fault> {
+        let register = self.float_register(register)?;
+        self.set_float_register(register, v);
+        Ok(())
+    }
+
+    fn load(&mut self, [register, high, low]: [u8; 3]) -> Result<(), Fault> {
+        self.write(register, u16::from_be_bytes([high, low]) as i32)
+    }
+
+    // writes the result of an operation that sets the flags
+    fn write_flagged(&mut self, register: u8, (v, flags): (i32, Flags)) -> Result<(), Fault> {
+        self.write(register, v)?;
+        self.flags = flags;
+        Ok(())
+    }
+
+    fn arithmetic(
+        &mut self,
+        [x, y, output]: [u8; 3],
+        f: fn(i32, i32) -> (i32, Flags),
+    ) -> Result<(), Fault> {
+        let (register_x, register_y) = self.read_two(x, y)?;
+        self.write_flagged(output, f(register_x, register_y))
+    }
+
+    fn add(&mut self, operands: [u8; 3]) -> Result<(), Fault> {
+        self.arithmetic(operands, flags::add)
+    }
+
+    // DIV and DIVU, which also keep the remainder for REM
+    fn div(
+        &mut self,
+        [x, y, output]: [u8; 3],
+        f: fn(i32, i32) -> (i32, Flags),
+        remainder: fn(i32, i32) -> i32,
+    ) -> Result<(), Fault> {
+        let (register_x, register_y) = self.read_two(x, y)?;
+        if register_y == 0 {
+            return Err(Fault::DivisionByZero);
+        }
+        self.write_flagged(output, f(register_x, register_y))?;
+        self.remainder = remainder(register_x, register_y);
+        Ok(())
+    }
+
+    fn rem(&mut self, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        self.write(register, self.remainder)
+    }
+
+    // MULW and MULWU, which write the low word to the output register and
+    // the high one to the register after it
+    fn mul_wide(
+        &mut self,
+        [x, y, output]: [u8; 3],
+        f: fn(i32, i32) -> ((i32, i32), Flags),
+    ) -> Result<(), Fault> {
+        let (register_x, register_y) = self.read_two(x, y)?;
+        self.register(output)?;
+        self.register(output + 1)?;
+        let ((low, high), flags) = f(register_x, register_y);
+        self.write(output + 1, high)?;
+        self.write(output, low)?;
+        self.flags = flags;
+        Ok(())
+    }
+
+    // ADC and SBC, which take in the carry flag
+    fn with_carry(
+        &mut self,
+        [x, y, output]: [u8; 3],
+        f: fn(i32, i32, bool) -> (i32, Flags),
+    ) -> Result<(), Fault> {
+        let (register_x, register_y) = self.read_two(x, y)?;
+        self.write_flagged(output, f(register_x, register_y, self.flags.carry))
+    }
+
+    fn load_float(&mut self, [register, high, low]: [u8; 3]) -> Result<(), Fault> {
+        let index = u16::from_be_bytes([high, low]);
+        self.float_register(register)?;
+        let v = *self
+            .constants
+            .get(index as usize)
+            .ok_or(Fault::InvalidConstant(index))?;
+        self.write_float(register, v)
+    }
+
+    // float arithmetic follows IEEE 754, so dividing by zero gives an
+    // infinity (or NaN) rather than a fault, and the flags are left alone
+    fn float_arithmetic(
+        &mut self,
+        [x, y, output]: [u8; 3],
+        f: fn(f64, f64) -> f64,
+    ) -> Result<(), Fault> {
+        let (register_x, register_y) = (self.read_float(x)?, self.read_float(y)?);
+        self.write_float(output, f(register_x, register_y))
+    }
+
+    // only the eq_flag: NaN is unordered, so every comparison with it but
+    // NEQF is false
+    fn compare_float(&mut self, [x, y, _]: [u8; 3], f: fn(f64, f64) -> bool) -> Result<(), Fault> {
+        let (register_x, register_y) = (self.read_float(x)?, self.read_float(y)?);
+        self.eq_flag = f(register_x, register_y);
+        Ok(())
+    }
+
+    fn int_to_float(&mut self, [x, output, _]: [u8; 3]) -> Result<(), Fault> {
+        let register_x = self.read(x)?;
+        self.write_float(output, register_x as f64)
+    }
+
+    // rounds toward zero, saturating at the ends of the i32 range. NaN gives 0
+    fn float_to_int(&mut self, [x, output, _]: [u8; 3]) -> Result<(), Fault> {
+        let register_x = self.read_float(x)?;
+        self.write(output, register_x as i32)
+    }
+
+    // the bitwise and shift instructions, which can't overflow
+    fn bitwise(&mut self, [x, y, output]: [u8; 3], f: fn(i32, i32) -> i32) -> Result<(), Fault> {
+        let (register_x, register_y) = self.read_two(x, y)?;
+        self.write(output, f(register_x, register_y))
+    }
+
+    fn not(&mut self, [x, output, _]: [u8; 3]) -> Result<(), Fault> {
+        let register_x = self.read(x)?;
+        self.write(output, !register_x)
+    }
+
+    // immediate arithmetic: x, the output register and a signed byte
+    fn arithmetic_immediate(
+        &mut self,
+        [x, output, immediate]: [u8; 3],
+        f: fn(i32, i32) -> (i32, Flags),
+    ) -> Result<(), Fault> {
+        let register_x = self.read(x)?;
+        self.write_flagged(output, f(register_x, immediate as i8 as i32))
+    }
+
+    // INC and DEC, which add or subtract 1 in place
+    fn step_by_one(
+        &mut self,
+        [register, ..]: [u8; 3],
+        f: fn(i32, i32) -> (i32, Flags),
+    ) -> Result<(), Fault> {
+        let v = self.read(register)?;
+        self.write_flagged(register, f(v, 1))
+    }
+
+    fn jump_to(&mut self, target: i64) -> Result<(), Fault> {
+        self.pc = usize::try_from(target).map_err(|_| Fault::InvalidJump(target))?;
+        Ok(())
+    }
+
+    pub(super) fn jump(&mut self, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let target = self.read(register)?;
+        self.jump_to(target as i64)
+    }
+
+    // relative jumps count from just past their register operand
+    pub(super) fn jump_forward(&mut self, pc: usize, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let offset = self.read(register)?;
+        self.jump_to(pc as i64 + 2 + offset as i64)
+    }
+
+    pub(super) fn jump_back(&mut self, pc: usize, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let offset = self.read(register)?;
+        self.jump_to(pc as i64 + 2 - offset as i64)
+    }
+
+    fn compare(&mut self, [x, y, _]: [u8; 3], f: impl Fn(i32, i32) -> bool) -> Result<(), Fault> {
+        let (register_x, register_y) = self.read_two(x, y)?;
+        self.eq_flag = f(register_x, register_y);
+        self.flags = flags::compare(register_x, register_y);
+        Ok(())
+    }
+
+    fn compare_immediate(
+        &mut self,
+        [x, high, low]: [u8; 3],
+        f: impl Fn(i32, i32) -> bool,
+    ) -> Result<(), Fault> {
+        let (register_x, y) = (self.read(x)?, i16::from_be_bytes([high, low]) as i32);
+        self.eq_flag = f(register_x, y);
+        self.flags = flags::compare(register_x, y);
+        Ok(())
+    }
+
+    pub(super) fn jeq(&mut self, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let target = self.read(register)?;
+        if self.eq_flag {
+            self.jump_to(target as i64)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn jneq(&mut self, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let target = self.read(register)?;
+        if !self.eq_flag {
+            self.jump_to(target as i64)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the relative branch `opcode` is taken in the current state.
+    pub(super) fn condition(&self, opcode: Opcode) -> bool {
+        let flags = self.flags;
+        match opcode {
+            Opcode::BEQ => self.eq_flag,
+            Opcode::BNEQ => !self.eq_flag,
+            Opcode::BZ => flags.zero,
+            Opcode::BNZ => !flags.zero,
+            Opcode::BN => flags.negative,
+            Opcode::BNN => !flags.negative,
+            Opcode::BC => flags.carry,
+            Opcode::BNC => !flags.carry,
+            Opcode::BV => flags.overflow,
+            Opcode::BNV => !flags.overflow,
+            _ => true,
+        }
+    }
+
+    // branches count from their own address
+    pub(super) fn branch(
+        &mut self,
+        pc: usize,
+        [high, low, _]: [u8; 3],
+        taken: bool,
+    ) -> Result<(), Fault> {
+        if taken {
+            self.jump_to(pc as i64 + i16::from_be_bytes([high, low]) as i64)?;
+        }
+        Ok(())
+    }
+
+    // Goes to entry `index` of the table of `count` instructions right after
+    // the JTABLE, usually branches. Any other index goes to the instruction
+    // right after the table, which is the default case.
+    pub(super) fn jump_table(
+        &mut self,
+        pc: usize,
+        [register, high, low]: [u8; 3],
+    ) -> Result<(), Fault> {
+        let index = self.read(register)?;
+        let count = u16::from_be_bytes([high, low]) as i32;
+        let entry = match (0..count).contains(&index) {
+            true => index,
+            false => count,
+        };
+        self.jump_to(pc as i64 + 4 + 4 * entry as i64)
+    }
+
+    // Decrements the counter and branches while it hasn't reached 0. The
+    // flags are left alone, and so is the counter if the branch faults. A
+    // counter of 0 or below wraps around, so the body runs about 2^32 times.
+    pub(super) fn count_down(
+        &mut self,
+        pc: usize,
+        [register, high, low]: [u8; 3],
+    ) -> Result<(), Fault> {
+        let counter = self.read(register)?.wrapping_sub(1);
+        self.branch(pc, [high, low, 0], counter != 0)?;
+        self.write(register, counter)
+    }
+
+    pub(super) fn alloc(&mut self, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let bytes = self.read(register)?;
+        let new_end = self.heap.len() as i64 + bytes as i64;
+        if new_end < 0 || new_end > i32::MAX as i64 {
+            return Err(Fault::InvalidAllocation(bytes));
+        }
+        let new_end = new_end as usize;
+        if let (Some(history), Some(tail)) = (&mut self.history, self.heap.get(new_end..)) {
+            history.record_heap_tail(tail);
+        }
+        self.heap.resize(new_end, 0);
+        Ok(())
+    }
+
+    fn string(&self, register: u8) -> Result<&str, Fault> {
+        heap_string(&self.heap, self.read(register)?)
+    }
+
+    pub(super) fn print_string(&mut self, [register, ..]: [u8; 3]) -> Result<(), Fault> {
+        let address = self.read(register)?;
+        self.output.push_str(heap_string(&self.heap, address)?);
+        Ok(())
+    }
+
+    // like the float comparisons, only the eq_flag is set
+    pub(super) fn compare_strings(&mut self, [x, y, _]: [u8; 3]) -> Result<(), Fault> {
+        self.eq_flag = self.string(x)? == self.string(y)?;
+        Ok(())
+    }
+
+    // The result is a new string at the end of the heap.
+    pub(super) fn concat_strings(&mut self, [x, y, output]: [u8; 3]) -> Result<(), Fault> {
+        let joined = [self.string(x)?, self.string(y)?].concat();
+        let output = self.register(output)?;
+        let address = self.heap.len();
+        if address + joined.len() + 1 > i32::MAX as usize {
+            let bytes = i32::try_from(joined.len() + 1).unwrap_or(i32::MAX);
+            return Err(Fault::InvalidAllocation(bytes));
+        }
+        self.heap.extend(joined.bytes());
+        self.heap.push(0);
+        self.set_register(output, address as i32);
+        Ok(())
+    }
+
+    // in bytes, not characters
+    pub(super) fn string_length(&mut self, [x, output, _]: [u8; 3]) -> Result<(), Fault> {
+        let len = self.string(x)?.len();
+        self.write(output, len as i32)
+    }
+
+    // a faulting instruction leaves the pc pointing at it
+    pub(super) fn continue_after(&mut self, pc: usize, result: Result<(), Fault>) -> Option<Outcome> {
+        match result {
+            Ok(()) => None,
+            Err(fault) => {
+                self.pc = pc;
+                Some(Outcome::Fault(fault))
+            }
+        }
+    }
+
+    /// Executes `instruction` as if it had been fetched from `pc`.
+    pub(super) fn execute(&mut self, pc: usize, instruction: Instruction) -> Option<Outcome> {
+        let operands = instruction.operands();
+        let opcode = instruction.opcode();
+        if matches!(opcode, Opcode::HLT | Opcode::ILGL) {
+            self.pc = pc + 1;
+        } else if pc + 4 > self.program.len() {
+            return self.continue_after(pc, Err(Fault::TruncatedInstruction));
+        } else {
+            self.pc = pc + 4;
+        }
+        let result = match opcode {
+            Opcode::HLT => return Some(Outcome::Halted),
+            Opcode::LOAD => self.load(operands),
+            Opcode::ADD => self.add(operands),
+            Opcode::SUB => self.arithmetic(operands, flags::sub),
+            Opcode::MUL => self.arithmetic(operands, flags::mul),
+            Opcode::DIV => self.div(operands, flags::div, i32::wrapping_rem),
+            Opcode::JMP => self.jump(operands),
+            Opcode::JMPF => self.jump_forward(pc, operands),
+            Opcode::JMPB => self.jump_back(pc, operands),
+            Opcode::EQ => self.compare(operands, |x, y| x == y),
+            Opcode::NEQ => self.compare(operands, |x, y| x != y),
+            Opcode::GT => self.compare(operands, |x, y| x > y),
+            Opcode::LT => self.compare(operands, |x, y| x < y),
+            Opcode::GTEQ => self.compare(operands, |x, y| x >= y),
+            Opcode::LTEQ => self.compare(operands, |x, y| x <= y),
+            Opcode::JEQ => self.jeq(operands),
+            Opcode::ALLOC => self.alloc(operands),
+            Opcode::AND => self.bitwise(operands, |x, y| x & y),
+            Opcode::OR => self.bitwise(operands, |x, y| x | y),
+            Opcode::XOR => self.bitwise(operands, |x, y| x ^ y),
+            Opcode::NOT => self.not(operands),
+            Opcode::SHL => self.bitwise(operands, shift_left),
+            Opcode::SHR => self.bitwise(operands, shift_right),
+            Opcode::SAR => self.bitwise(operands, shift_right_arithmetic),
+            Opcode::ADDI => self.arithmetic_immediate(operands, flags::add),
+            Opcode::SUBI => self.arithmetic_immediate(operands, flags::sub),
+            Opcode::MULI => self.arithmetic_immediate(operands, flags::mul),
+            Opcode::EQI => self.compare_immediate(operands, |x, y| x == y),
+            Opcode::NEQI => self.compare_immediate(operands, |x, y| x != y),
+            Opcode::GTI => self.compare_immediate(operands, |x, y| x > y),
+            Opcode::LTI => self.compare_immediate(operands, |x, y| x < y),
+            Opcode::GTEQI => self.compare_immediate(operands, |x, y| x >= y),
+            Opcode::LTEQI => self.compare_immediate(operands, |x, y| x <= y),
+            Opcode::INC => self.step_by_one(operands, flags::add),
+            Opcode::DEC => self.step_by_one(operands, flags::sub),
+            Opcode::JNEQ => self.jneq(operands),
+            Opcode::DIVU => self.div(operands, flags::div_unsigned, remainder_unsigned),
+            Opcode::GTU => self.compare(operands, |x, y| (x as u32) > (y as u32)),
+            Opcode::LTU => self.compare(operands, |x, y| (x as u32) < (y as u32)),
+            Opcode::GTEQU => self.compare(operands, |x, y| (x as u32) >= (y as u32)),
+            Opcode::LTEQU => self.compare(operands, |x, y| (x as u32) <= (y as u32)),
+            Opcode::REM => self.rem(operands),
+            Opcode::MULW => self.mul_wide(operands, flags::mul_wide),
+            Opcode::MULWU => self.mul_wide(operands, flags::mul_wide_unsigned),
+            Opcode::ADC => self.with_carry(operands, flags::add_with_carry),
+            Opcode::SBC => self.with_carry(operands, flags::sub_with_borrow),
+            Opcode::LOADF => self.load_float(operands),
+            Opcode::ADDF => self.float_arithmetic(operands, |x, y| x + y),
+            Opcode::SUBF => self.float_arithmetic(operands, |x, y| x - y),
+            Opcode::MULF => self.float_arithmetic(operands, |x, y| x * y),
+            Opcode::DIVF => self.float_arithmetic(operands, |x, y| x / y),
+            Opcode::EQF => self.compare_float(operands, |x, y| x == y),
+            Opcode::NEQF => self.compare_float(operands, |x, y| x != y),
+            Opcode::GTF => self.compare_float(operands, |x, y| x > y),
+            Opcode::LTF => self.compare_float(operands, |x, y| x < y),
+            Opcode::GTEQF => self.compare_float(operands, |x, y| x >= y),
+            Opcode::LTEQF => self.compare_float(operands, |x, y| x <= y),
+            Opcode::ITOF => self.int_to_float(operands),
+            Opcode::FTOI => self.float_to_int(operands),
+            Opcode::JTABLE => self.jump_table(pc, operands),
+            Opcode::LOOP => self.count_down(pc, operands),
+            Opcode::PRTS => self.print_string(operands),
+            Opcode::STREQ => self.compare_strings(operands),
+            Opcode::STRCAT => self.concat_strings(operands),
+            Opcode::STRLEN => self.string_length(operands),
+            Opcode::BRA
+            | Opcode::BEQ
+            | Opcode::BNEQ
+            | Opcode::BZ
+            | Opcode::BNZ
+            | Opcode::BN
+            | Opcode::BNN
+            | Opcode::BC
+            | Opcode::BNC
+            | Opcode::BV
+            | Opcode::BNV => self.branch(pc, operands, self.condition(opcode)),
+            Opcode::ILGL => return Some(Outcome::Fault(Fault::IllegalOpcode(self.program[pc]))),
+        };
+        self.continue_after(pc, result)
+    }
+}
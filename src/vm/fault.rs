@@ -0,0 +1,44 @@
+use std::fmt;
+
+/// Something the program did that the VM refuses to carry out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// the byte at the program counter is not a known opcode
+    IllegalOpcode(u8),
+    /// an operand names a register past the last one
+    InvalidRegister(u8),
+    DivisionByZero,
+    /// the program ends in the middle of an instruction
+    TruncatedInstruction,
+    /// a jump would leave the program counter below 0
+    InvalidJump(i64),
+    /// ALLOC asked for a size the heap cannot grow or shrink to
+    InvalidAllocation(i32),
+    /// LOADF names a constant past the end of the constant pool
+    InvalidConstant(u16),
+    /// a string instruction's address is outside the heap
+    InvalidAddress(i32),
+    /// no NUL byte ends the string starting at this address
+    UnterminatedString(usize),
+    /// a string has bytes that aren't UTF-8, starting at this address
+    InvalidUtf8(usize),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::IllegalOpcode(op) => write!(f, "unknown opcode {op}"),
+            Fault::InvalidRegister(r) => write!(f, "there is no register {r}"),
+            Fault::DivisionByZero => write!(f, "division by zero"),
+            Fault::TruncatedInstruction => write!(f, "the program ends mid-instruction"),
+            Fault::InvalidJump(target) => write!(f, "cannot jump to {target}"),
+            Fault::InvalidAllocation(bytes) => write!(f, "cannot allocate {bytes} bytes"),
+            Fault::InvalidConstant(index) => write!(f, "there is no constant {index}"),
+            Fault::InvalidAddress(address) => write!(f, "there is no heap address {address}"),
+            Fault::UnterminatedString(address) => {
+                write!(f, "the string at {address} runs off the end of the heap")
+            }
+            Fault::InvalidUtf8(address) => write!(f, "invalid UTF-8 at heap address {address}"),
+        }
+    }
+}
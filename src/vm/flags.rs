@@ -0,0 +1,198 @@
+// The status register, and the arithmetic that sets it. Everything wraps
+// around on overflow; the flags are how a program finds out it happened.
+use std::fmt;
+
+/// Condition flags set by arithmetic and comparisons. Bitwise operations and
+/// shifts leave them alone.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// Z: the result was 0
+    pub zero: bool,
+    /// N: the result was negative
+    pub negative: bool,
+    /// C: an addition carried out of the top bit, or a subtraction borrowed
+    /// into it, taking both operands as unsigned. Multiplication sets it
+    /// along with V
+    pub carry: bool,
+    /// V: the signed result did not fit in 32 bits
+    pub overflow: bool,
+}
+
+impl Flags {
+    fn of(result: i32, carry: bool, overflow: bool) -> Flags {
+        Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        }
+    }
+
+    /// Packs the flags into the low four bits, Z first.
+    pub fn bits(self) -> u8 {
+        self.zero as u8
+            | (self.negative as u8) << 1
+            | (self.carry as u8) << 2
+            | (self.overflow as u8) << 3
+    }
+
+    /// Unpacks what `bits` packed, or None if any other bit is set.
+    pub fn from_bits(bits: u8) -> Option<Flags> {
+        if bits > 0xf {
+            return None;
+        }
+        Some(Flags {
+            zero: bits & 1 != 0,
+            negative: bits & 2 != 0,
+            carry: bits & 4 != 0,
+            overflow: bits & 8 != 0,
+        })
+    }
+}
+
+// set flags by their letter, clear ones as '-'
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (self.zero, 'Z'),
+            (self.negative, 'N'),
+            (self.carry, 'C'),
+            (self.overflow, 'V'),
+        ];
+        for (set, letter) in flags {
+            write!(f, "{}", if set { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn add(x: i32, y: i32) -> (i32, Flags) {
+    let (result, overflow) = x.overflowing_add(y);
+    let carry = (x as u32).checked_add(y as u32).is_none();
+    (result, Flags::of(result, carry, overflow))
+}
+
+pub(super) fn sub(x: i32, y: i32) -> (i32, Flags) {
+    let (result, overflow) = x.overflowing_sub(y);
+    (result, Flags::of(result, (x as u32) < (y as u32), overflow))
+}
+
+pub(super) fn mul(x: i32, y: i32) -> (i32, Flags) {
+    let (result, overflow) = x.overflowing_mul(y);
+    (result, Flags::of(result, overflow, overflow))
+}
+
+/// Only `i32::MIN / -1` overflows. The divisor must not be 0.
+pub(super) fn div(x: i32, y: i32) -> (i32, Flags) {
+    let (result, overflow) = x.overflowing_div(y);
+    (result, Flags::of(result, false, overflow))
+}
+
+/// DIVU, which takes both operands as unsigned. The divisor must not be 0.
+pub(super) fn div_unsigned(x: i32, y: i32) -> (i32, Flags) {
+    let result = ((x as u32) / (y as u32)) as i32;
+    (result, Flags::of(result, false, false))
+}
+
+/// `x + y + carry`, for the upper words of a wider addition.
+pub(super) fn add_with_carry(x: i32, y: i32, carry: bool) -> (i32, Flags) {
+    let unsigned = x as u32 as i64 + y as u32 as i64 + carry as i64;
+    let signed = x as i64 + y as i64 + carry as i64;
+    let result = unsigned as i32;
+    (
+        result,
+        Flags::of(result, unsigned > u32::MAX as i64, signed != result as i64),
+    )
+}
+
+/// `x - y - borrow`, for the upper words of a wider subtraction. The borrow
+/// is the carry flag a subtraction leaves.
+pub(super) fn sub_with_borrow(x: i32, y: i32, borrow: bool) -> (i32, Flags) {
+    let unsigned = x as u32 as i64 - y as u32 as i64 - borrow as i64;
+    let signed = x as i64 - y as i64 - borrow as i64;
+    let result = unsigned as i32;
+    (result, Flags::of(result, unsigned < 0, signed != result as i64))
+}
+
+// Z and N describe the whole 64-bit product, which can't overflow
+fn wide(product: i64) -> ((i32, i32), Flags) {
+    let flags = Flags {
+        zero: product == 0,
+        negative: product < 0,
+        ..Flags::default()
+    };
+    ((product as i32, (product >> 32) as i32), flags)
+}
+
+/// The full signed product as its low and high words.
+pub(super) fn mul_wide(x: i32, y: i32) -> ((i32, i32), Flags) {
+    wide(x as i64 * y as i64)
+}
+
+/// The full product of both operands taken as unsigned, as its low and high
+/// words.
+pub(super) fn mul_wide_unsigned(x: i32, y: i32) -> ((i32, i32), Flags) {
+    wide((x as u32 as u64 * y as u32 as u64) as i64)
+}
+
+/// The flags a comparison sets, which are those of `x - y`.
+pub(super) fn compare(x: i32, y: i32) -> Flags {
+    sub(x, y).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic_flags() {
+        assert_eq!(add(1, 2), (3, Flags::default()));
+        assert_eq!(add(-1, 1).1.to_string(), "Z-C-");
+        assert_eq!(add(i32::MAX, 1), (i32::MIN, Flags::of(i32::MIN, false, true)));
+        assert_eq!(add(i32::MIN, -1).1.to_string(), "--CV");
+        assert_eq!(sub(0, 1).1.to_string(), "-NC-");
+        assert_eq!(sub(i32::MIN, 1).1.to_string(), "---V");
+        assert_eq!(sub(5, 5).1.to_string(), "Z---");
+        assert_eq!(mul(0x10000, 0x10000).1.to_string(), "Z-CV");
+        assert_eq!(mul(-3, 4), (-12, Flags::of(-12, false, false)));
+        assert_eq!(div(i32::MIN, -1).1.to_string(), "-N-V");
+        assert_eq!(compare(-1, 1).to_string(), "-N--");
+    }
+
+    #[test]
+    fn test_wide_arithmetic() {
+        // 0x1_ffff_ffff + 1 a word at a time
+        let (low, flags) = add(-1, 1);
+        assert_eq!(add_with_carry(1, 0, flags.carry), (2, Flags::default()));
+        assert_eq!(add_with_carry(i32::MAX, 0, true).1.to_string(), "-N-V");
+        assert_eq!(add_with_carry(-1, -1, true), (-1, Flags::of(-1, true, false)));
+        assert_eq!(low, 0);
+        // 0x1_0000_0000 - 1
+        let (low, flags) = sub(0, 1);
+        assert_eq!(low, -1);
+        assert_eq!(sub_with_borrow(1, 0, flags.carry), (0, Flags::of(0, false, false)));
+        assert_eq!(sub_with_borrow(0, 0, true).1.to_string(), "-NC-");
+        assert_eq!(sub_with_borrow(i32::MIN, 0, true).1.to_string(), "---V");
+        assert_eq!(sub_with_borrow(5, 5, false), sub(5, 5));
+
+        assert_eq!(mul_wide(i32::MIN, i32::MIN).0, (0, 0x4000_0000));
+        assert_eq!(mul_wide(-1, 1), ((-1, -1), Flags::of(-1, false, false)));
+        assert_eq!(mul_wide_unsigned(-1, -1).0, (1, -2));
+        assert_eq!(mul_wide_unsigned(-1, 2).1.to_string(), "----");
+        assert_eq!(mul_wide(0, -7).1.to_string(), "Z---");
+        assert_eq!(div_unsigned(-2, 2).0, i32::MAX);
+        assert_eq!(div_unsigned(7, -1).0, 0);
+    }
+
+    #[test]
+    fn test_bits() {
+        let flags = sub(i32::MIN, 1).1;
+        assert_eq!(flags.bits(), 0b1000);
+        assert_eq!(Flags::from_bits(0b0101).unwrap().to_string(), "Z-C-");
+        for bits in 0..16 {
+            assert_eq!(Flags::from_bits(bits).unwrap().bits(), bits);
+        }
+        assert_eq!(Flags::from_bits(0x10), None);
+    }
+}
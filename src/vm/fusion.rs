@@ -0,0 +1,163 @@
+// Superinstructions: pairs of instructions that show up together often
+// enough to be worth dispatching as one.
+use crate::instructions::{Instruction, Opcode};
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Superinstruction {
+    /// a comparison followed by a JEQ on its result
+    CompareJump {
+        compare: fn(i32, i32) -> bool,
+        operands: [u8; 3],
+        target: [u8; 3],
+    },
+    /// a LOAD followed by an ADD
+    LoadAdd { load: [u8; 3], add: [u8; 3] },
+}
+
+fn comparison(opcode: Opcode) -> Option<fn(i32, i32) -> bool> {
+    Some(match opcode {
+        Opcode::EQ => |x, y| x == y,
+        Opcode::NEQ => |x, y| x != y,
+        Opcode::GT => |x, y| x > y,
+        Opcode::LT => |x, y| x < y,
+        Opcode::GTEQ => |x, y| x >= y,
+        Opcode::LTEQ => |x, y| x <= y,
+        _ => return None,
+    })
+}
+
+/// The superinstruction `first` and `second` fuse into, if any.
+pub(super) fn fuse(first: Instruction, second: Instruction) -> Option<Superinstruction> {
+    match (first.opcode(), second.opcode()) {
+        (compare, Opcode::JEQ) => Some(Superinstruction::CompareJump {
+            compare: comparison(compare)?,
+            operands: first.operands(),
+            target: second.operands(),
+        }),
+        (Opcode::LOAD, Opcode::ADD) => Some(Superinstruction::LoadAdd {
+            load: first.operands(),
+            add: second.operands(),
+        }),
+        _ => None,
+    }
+}
+
+/// Fuses the instructions in `decoded` from slot `start` on. Each slot holds
+/// the superinstruction starting there, so a jump into the middle of a pair
+/// still finds the plain second instruction.
+pub(super) fn fuse_from(
+    decoded: &[Instruction],
+    fused: &mut Vec<Option<Superinstruction>>,
+    start: usize,
+) {
+    fused.truncate(start);
+    fused.extend((start..decoded.len()).map(|slot| {
+        let second = decoded.get(slot + 1)?;
+        fuse(decoded[slot], *second)
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::differential::{assert_same, assert_same_steps, random_programs};
+    use crate::vm::{Outcome, Stdin, VM};
+
+    fn fused(vm: &mut VM) {
+        vm.set_fusion(true);
+    }
+
+    #[test]
+    fn test_fuse() {
+        let code = assemble("lt $0 $1\njeq $2\nload $3 #4\nadd $3 $3 $3\nadd $0 $0 $0")
+            .unwrap()
+            .code;
+        let decoded: Vec<Instruction> = code.chunks_exact(4).map(Instruction::decode).collect();
+        let mut fused = vec![];
+        fuse_from(&decoded, &mut fused, 0);
+        assert!(matches!(fused[0], Some(Superinstruction::CompareJump { .. })));
+        assert!(fused[1].is_none());
+        assert!(matches!(fused[2], Some(Superinstruction::LoadAdd { .. })));
+        assert!(fused[3].is_none() && fused[4].is_none());
+    }
+
+    #[test]
+    fn test_fused_loop() {
+        // counts $0 down from 50, summing 3 into $4 each time around
+        let code = assemble(
+            "load $0 #50
+             load $1 #1
+             load $2 #0
+             load $3 #12
+             load $5 #3
+             add $4 $5 $4
+             sub $0 $1 $0
+             gt $0 $2
+             jeq $3",
+        )
+        .unwrap()
+        .code;
+        let mut vm = VM::new();
+        vm.stdin(code.iter().copied());
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[4], 150);
+        assert_same(&code, fused, |_| ());
+        for fuel in 0..40 {
+            assert_same(&code, fused, |vm| vm.set_fuel(Some(fuel)));
+        }
+    }
+
+    #[test]
+    fn test_fused_edge_cases() {
+        let none = |_: &mut VM| ();
+        let compare_jump = [9, 0, 1, 0, 15, 2, 0, 0];
+        // a breakpoint on the second half of a pair still stops the VM
+        assert_same(&compare_jump, fused, |vm| vm.add_breakpoint(4));
+        let mut vm = VM::new();
+        vm.stdin(compare_jump);
+        vm.add_breakpoint(4);
+        assert!(matches!(vm.run(), Outcome::Breakpoint(_)));
+        assert_eq!(vm.pc(), 4);
+        // the second half faults with the first one already done
+        assert_same(&[9, 0, 1, 0, 15, 40, 0, 0], fused, none);
+        assert_same(&[1, 0, 0, 1, 2, 0, 0, 99], fused, none);
+        // the first half faults
+        assert_same(&[1, 99, 0, 1, 2, 0, 0, 1], fused, none);
+        // jumping into the middle of a pair
+        assert_same(&[6, 1, 0, 0, 1, 0, 0, 7, 2, 0, 0, 3], fused, |vm| {
+            vm.registers[1] = 8
+        });
+    }
+
+    #[test]
+    fn test_single_step_splits_pairs() {
+        let mut vm = VM::new();
+        vm.stdin([9, 0, 1, 0, 15, 2, 0, 0, 1, 3, 0, 1]);
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.pc(), 4);
+        assert_same_steps(&[9, 0, 1, 0, 15, 2, 0, 0, 1, 3, 0, 1], fused, |_| (), 10);
+        assert_same_steps(&[1, 0, 0, 1, 2, 0, 0, 1], fused, |_| (), 10);
+        for code in random_programs(0x1f35_9a0b, 200) {
+            assert_same_steps(&code, fused, |_| (), 40);
+        }
+    }
+
+    #[test]
+    fn test_fused_program_grows() {
+        let mut vm = VM::new();
+        vm.stdin([1, 0, 0, 2]);
+        vm.run();
+        vm.stdin([2, 0, 0, 1]);
+        vm.pc = 0;
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[1], 4);
+    }
+
+    #[test]
+    fn test_fusion_differential() {
+        for code in random_programs(0x2545_f491, 500) {
+            assert_same(&code, fused, |vm| vm.set_fuel(Some(40)));
+        }
+    }
+}
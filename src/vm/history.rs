@@ -0,0 +1,76 @@
+use super::Flags;
+use std::collections::VecDeque;
+
+// Everything one instruction may change, as it was before the instruction ran.
+// Registers are logged as they get written, other fields are cheap enough to
+// copy up front.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Undo {
+    pub pc: usize,
+    pub stdout: usize,
+    pub eq_flag: bool,
+    pub flags: Flags,
+    pub remainder: i32,
+    pub heap_len: usize,
+    // the bytes a shrinking ALLOC cut off the end of the heap
+    pub heap_tail: Vec<u8>,
+    pub output_len: usize,
+    pub registers: Vec<(usize, i32)>,
+    pub float_registers: Vec<(usize, f64)>,
+}
+
+/// Bounded undo log: once full, the oldest steps are forgotten.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct History {
+    steps: VecDeque<Undo>,
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            steps: VecDeque::new(),
+            limit,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn push(&mut self, undo: Undo) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.steps.len() == self.limit {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(undo);
+    }
+
+    pub fn record_register(&mut self, register: usize, old: i32) {
+        if let Some(undo) = self.steps.back_mut() {
+            undo.registers.push((register, old));
+        }
+    }
+
+    pub fn record_float_register(&mut self, register: usize, old: f64) {
+        if let Some(undo) = self.steps.back_mut() {
+            undo.float_registers.push((register, old));
+        }
+    }
+
+    pub fn record_heap_tail(&mut self, tail: &[u8]) {
+        if let Some(undo) = self.steps.back_mut() {
+            undo.heap_tail.extend_from_slice(tail);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Undo> {
+        self.steps.pop_back()
+    }
+}
@@ -0,0 +1,78 @@
+// Executable memory straight from the kernel. Pages are mapped writable,
+// filled, then flipped to read+execute, so they are never both at once.
+use std::ffi::c_void;
+use std::ptr;
+
+mod sys {
+    use std::ffi::c_void;
+
+    pub const PROT_READ: i32 = 1;
+    pub const PROT_WRITE: i32 = 2;
+    pub const PROT_EXEC: i32 = 4;
+    pub const MAP_PRIVATE: i32 = 2;
+    pub const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        pub fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+}
+
+pub(super) struct ExecutableMemory {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// the mapping is never written after it becomes executable
+unsafe impl Send for ExecutableMemory {}
+unsafe impl Sync for ExecutableMemory {}
+
+impl ExecutableMemory {
+    /// Copies `code` into fresh executable pages. `None` if the kernel won't
+    /// hand them out.
+    pub(super) fn new(code: &[u8]) -> Option<ExecutableMemory> {
+        let len = code.len().max(1);
+        let ptr = unsafe {
+            sys::mmap(
+                ptr::null_mut(),
+                len,
+                sys::PROT_READ | sys::PROT_WRITE,
+                sys::MAP_PRIVATE | sys::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        // MAP_FAILED
+        if ptr as isize == -1 {
+            return None;
+        }
+        let memory = ExecutableMemory { ptr, len };
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr.cast::<u8>(), code.len());
+            if sys::mprotect(ptr, len, sys::PROT_READ | sys::PROT_EXEC) != 0 {
+                return None;
+            }
+        }
+        Some(memory)
+    }
+
+    pub(super) fn as_ptr(&self) -> *const u8 {
+        self.ptr.cast()
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe {
+            sys::munmap(self.ptr, self.len);
+        }
+    }
+}
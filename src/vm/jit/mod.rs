@@ -0,0 +1,311 @@
+// A JIT for hot basic blocks. Every aligned address counts how often the VM
+// gets there, and once that passes a threshold the block starting there is
+// translated to native code. Blocks hold arithmetic, comparisons and a jump
+// at most; everything else is left to the interpreter.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod memory;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod x86_64;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use x86_64::NativeCode;
+
+use super::{Outcome, VM};
+use crate::instructions::{Instruction, Layout, Opcode};
+use std::sync::Arc;
+
+// nothing to run natively anywhere else
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+enum NativeCode {}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+impl NativeCode {
+    fn new(_: &[(usize, Instruction)], _: usize) -> Option<NativeCode> {
+        None
+    }
+
+    unsafe fn call(&self, _: *mut i32, _: *mut bool, _: *mut super::Flags) -> i64 {
+        match *self {}
+    }
+}
+
+/// How many times a block has to be reached before it gets compiled.
+pub(super) const HOT: u32 = 50;
+
+struct NativeBlock {
+    code: NativeCode,
+    // fuel for everything after the first instruction, which `step` pays for
+    cost: u64,
+    // address of the last instruction, the only one that can fault
+    last: usize,
+    // where the block falls through to
+    end: usize,
+    // the last register the block writes to
+    stdout: Option<usize>,
+}
+
+#[derive(Clone)]
+enum Slot {
+    Cold(u32),
+    Native(Arc<NativeBlock>),
+    Interpret,
+}
+
+pub(super) struct Jit {
+    slots: Vec<Slot>,
+    pub(super) threshold: u32,
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Jit {
+            slots: vec![],
+            threshold: HOT,
+        }
+    }
+}
+
+impl Jit {
+    pub(super) fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    pub(super) fn reset(&mut self, slots: usize) {
+        self.slots = vec![Slot::Cold(0); slots];
+    }
+}
+
+fn ends_block(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ | Opcode::JNEQ | Opcode::LOOP
+    ) || opcode.layout() == Layout::Branch
+}
+
+// Only instructions that can't fault get compiled, besides jumps: a jump
+// to a negative address is handed back to the interpreter. That would count
+// a LOOP down twice, so those stay interpreted.
+fn is_supported(pc: usize, instruction: Instruction) -> bool {
+    let [a, b, c] = instruction.operands();
+    let valid = |registers: &[u8]| registers.iter().all(|&r| r < 32);
+    // pcs have to fit in 32 bits in the generated code
+    pc + 8 <= i32::MAX as usize
+        && match instruction.opcode() {
+            Opcode::LOAD => valid(&[a]),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::AND | Opcode::OR | Opcode::XOR => {
+                valid(&[a, b, c])
+            }
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => valid(&[a, b]),
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTEQ | Opcode::LTEQ => {
+                valid(&[a, b])
+            }
+            Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::LTI
+            | Opcode::GTEQI
+            | Opcode::LTEQI
+            | Opcode::INC
+            | Opcode::DEC => valid(&[a]),
+            opcode if opcode.layout() == Layout::Branch => true,
+            Opcode::LOOP => valid(&[a]) && pc as i64 + i16::from_be_bytes([b, c]) as i64 >= 0,
+            opcode => ends_block(opcode) && valid(&[a]),
+        }
+}
+
+fn compile_block(decoded: &[Instruction], costs: &[u64; 256], slot: usize) -> Option<NativeBlock> {
+    let mut block = vec![];
+    for (pc, instruction) in decoded.iter().enumerate().skip(slot).map(|(s, i)| (s * 4, *i)) {
+        if !is_supported(pc, instruction) {
+            break;
+        }
+        block.push((pc, instruction));
+        if ends_block(instruction.opcode()) {
+            break;
+        }
+    }
+    let &(last, _) = block.last()?;
+    let end = last + 4;
+    let stdout = block
+        .iter()
+        .rev()
+        .find_map(|(_, instruction)| match instruction.opcode() {
+            Opcode::LOAD | Opcode::INC | Opcode::DEC | Opcode::LOOP => {
+                Some(instruction.operands()[0] as usize)
+            }
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => Some(instruction.operands()[1] as usize),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::AND | Opcode::OR | Opcode::XOR => {
+                Some(instruction.operands()[2] as usize)
+            }
+            _ => None,
+        });
+    Some(NativeBlock {
+        code: NativeCode::new(&block, end)?,
+        cost: block[1..]
+            .iter()
+            .map(|(_, instruction)| costs[instruction.opcode() as usize])
+            .sum(),
+        last,
+        end,
+        stdout,
+    })
+}
+
+impl VM {
+    /// Counts a visit to `pc` and returns its native block, compiling it if
+    /// it just got hot.
+    fn hot_block(&mut self, pc: usize) -> Option<Arc<NativeBlock>> {
+        if !pc.is_multiple_of(4) {
+            return None;
+        }
+        let slot = pc / 4;
+        match self.jit.slots.get(slot)? {
+            Slot::Native(block) => Some(block.clone()),
+            Slot::Interpret => None,
+            Slot::Cold(visits) if visits + 1 < self.jit.threshold => {
+                self.jit.slots[slot] = Slot::Cold(visits + 1);
+                None
+            }
+            Slot::Cold(_) => {
+                let block = compile_block(&self.decoded, &self.costs, slot).map(Arc::new);
+                self.jit.slots[slot] = match &block {
+                    Some(block) => Slot::Native(block.clone()),
+                    None => Slot::Interpret,
+                };
+                block
+            }
+        }
+    }
+
+    /// Runs the native block at the pc if it is hot, like `execute_compiled`
+    /// does with closures, and interprets one instruction otherwise.
+    pub(super) fn execute_jit(&mut self) -> Option<Outcome> {
+        let pc = self.pc;
+        let block = match self.hot_block(pc) {
+            Some(block)
+                if self.fuel.is_none_or(|fuel| fuel >= block.cost)
+                    && self
+                        .breakpoints
+                        .addresses()
+                        .range(pc + 1..block.end)
+                        .next()
+                        .is_none() =>
+            {
+                block
+            }
+            _ => return self.interpret_next(),
+        };
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= block.cost;
+        }
+        let next = unsafe {
+            block
+                .code
+                .call(self.registers.as_mut_ptr(), &mut self.eq_flag, &mut self.flags)
+        };
+        if let Some(register) = block.stdout {
+            self.stdout = register;
+        }
+        if next < 0 {
+            // the final jump faults, let the interpreter report it
+            return self.execute(block.last, self.fetch(block.last));
+        }
+        self.pc = next as usize;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::differential::{assert_same, random_programs};
+    use crate::vm::{Backend, Fault, Stdin};
+
+    fn jit(vm: &mut VM) {
+        vm.set_backend(Backend::Jit);
+    }
+
+    // compiles every block the first time it is reached
+    fn eager_jit(vm: &mut VM) {
+        jit(vm);
+        vm.jit.threshold = 0;
+    }
+
+    const LOOP: &str = "load $0 #500
+                        load $1 #1
+                        load $2 #0
+                        load $3 #12
+                        load $5 #3
+                        add $4 $5 $4
+                        sub $0 $1 $0
+                        gt $0 $2
+                        jeq $3";
+
+    #[test]
+    fn test_hot_loop() {
+        let code = assemble(LOOP).unwrap().code;
+        let mut vm = VM::new();
+        jit(&mut vm);
+        vm.stdin(code.iter().copied());
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[4], 1500);
+        assert_eq!(vm.stdout(), 0);
+        if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            assert!(matches!(vm.jit.slots[3], Slot::Native(_)));
+        }
+        assert!(matches!(vm.jit.slots[0], Slot::Cold(1)));
+        assert_same(&code, jit, |_| ());
+        for fuel in (0..3000).step_by(7) {
+            assert_same(&code, jit, |vm| vm.set_fuel(Some(fuel)));
+        }
+    }
+
+    #[test]
+    fn test_jit_edge_cases() {
+        let none = |_: &mut VM| ();
+        // a jump to a negative address faults in the interpreter
+        let code = [1, 0, 0, 8, 3, 1, 0, 1, 6, 1, 0, 0];
+        assert_same(&code, eager_jit, none);
+        let mut vm = VM::new();
+        eager_jit(&mut vm);
+        vm.stdin(code);
+        assert_eq!(vm.run(), Outcome::Fault(Fault::InvalidJump(-8)));
+        assert_eq!((vm.pc(), vm.stdout()), (8, -8));
+        assert_same(&[1, 0, 0, 8, 8, 0, 0, 0], eager_jit, none);
+        assert_same(&[1, 0, 0, 200, 7, 1, 0, 0], eager_jit, |vm| {
+            vm.registers[1] = -500
+        });
+        assert_same(&[1, 0, 0, 7, 1, 1, 0, 6, 4, 0, 1, 2], eager_jit, none);
+        // unsupported instructions end the block
+        assert_same(&[1, 0, 0, 8, 5, 0, 1, 2, 1, 1, 0, 3], eager_jit, none);
+        assert_same(&[1, 0, 0, 8, 16, 0, 0, 0, 0, 0, 0, 0], eager_jit, none);
+        assert_same(&[1, 0, 0, 8, 2, 0, 0, 40], eager_jit, none);
+        // a LOOP back out of the program is left to the interpreter
+        assert_same(&[1, 0, 0, 2, 71, 0, 0xff, 0xf8], eager_jit, none);
+        let code = assemble("load $0 #300\n.loop $0\ninc $1\n.endloop\nhlt")
+            .unwrap()
+            .code;
+        assert_same(&code, jit, none);
+        assert_same(&code, eager_jit, |vm| vm.set_fuel(Some(100)));
+        // breakpoints and fuel
+        let code = assemble(LOOP).unwrap().code;
+        assert_same(&code, eager_jit, |vm| vm.add_breakpoint(16));
+        assert_same(&code, eager_jit, |vm| {
+            vm.set_fuel(Some(100));
+            vm.set_cost(Opcode::SUB, 7);
+        });
+    }
+
+    #[test]
+    fn test_jit_differential() {
+        for code in random_programs(0x1b87_3593, 500) {
+            assert_same(&code, eager_jit, |vm| vm.set_fuel(Some(40)));
+            assert_same(&code, jit, |vm| vm.set_fuel(Some(40)));
+        }
+    }
+}
@@ -0,0 +1,326 @@
+// Translates basic blocks to x86-64. A compiled block is a System V function
+// taking the register file in rdi, the eq_flag in rsi and the flags in rdx,
+// which returns the pc to carry on from in rax. A negative pc means the jump
+// ending the block would fault, so the interpreter should run that jump
+// instead.
+use super::memory::ExecutableMemory;
+use crate::instructions::{Instruction, Layout, Opcode};
+use crate::vm::Flags;
+use std::mem::offset_of;
+
+type Entry = unsafe extern "sysv64" fn(*mut i32, *mut bool, *mut Flags) -> i64;
+
+pub(super) struct NativeCode {
+    memory: ExecutableMemory,
+}
+
+impl NativeCode {
+    /// Compiles `block`, a run of instructions `is_supported` accepted, which
+    /// falls through to `end`.
+    pub(super) fn new(block: &[(usize, Instruction)], end: usize) -> Option<NativeCode> {
+        let memory = ExecutableMemory::new(&translate(block, end))?;
+        Some(NativeCode { memory })
+    }
+
+    /// # Safety
+    /// `registers` must point at all 32 registers.
+    pub(super) unsafe fn call(
+        &self,
+        registers: *mut i32,
+        eq_flag: *mut bool,
+        flags: *mut Flags,
+    ) -> i64 {
+        let entry: Entry = std::mem::transmute(self.memory.as_ptr());
+        entry(registers, eq_flag, flags)
+    }
+}
+
+// [rdi + disp8] addressing for a VM register
+fn register(r: u8) -> u8 {
+    r * 4
+}
+
+fn imm32(code: &mut Vec<u8>, v: i64) {
+    code.extend_from_slice(&(v as i32).to_le_bytes());
+}
+
+// mov rax, imm32; ret
+fn return_pc(code: &mut Vec<u8>, pc: usize) {
+    code.extend_from_slice(&[0x48, 0xc7, 0xc0]);
+    imm32(code, pc as i64);
+    code.push(0xc3);
+}
+
+fn branch_target(pc: usize, high: u8, low: u8) -> i64 {
+    pc as i64 + i16::from_be_bytes([high, low]) as i64
+}
+
+// [rdx + disp8] addressing for each flag
+const ZERO: u8 = offset_of!(Flags, zero) as u8;
+const NEGATIVE: u8 = offset_of!(Flags, negative) as u8;
+const CARRY: u8 = offset_of!(Flags, carry) as u8;
+const OVERFLOW: u8 = offset_of!(Flags, overflow) as u8;
+
+// setz, sets, setc and seto into the flags, straight after the instruction
+// that set them
+fn set_flags(code: &mut Vec<u8>) {
+    code.extend_from_slice(&[0x0f, 0x94, 0x42, ZERO, 0x0f, 0x98, 0x42, NEGATIVE]);
+    code.extend_from_slice(&[0x0f, 0x92, 0x42, CARRY, 0x0f, 0x90, 0x42, OVERFLOW]);
+}
+
+// Ends a conditional jump whose target is in rax: cmp byte [rsi], 0 (or the
+// flag it tests); jne (je when branching on a clear one) over the fall
+// through to pc + 4; ret
+fn conditional(code: &mut Vec<u8>, pc: usize, op: Opcode) {
+    let (flag, jcc) = match op {
+        Opcode::JEQ | Opcode::BEQ => (None, 0x75),
+        Opcode::JNEQ | Opcode::BNEQ => (None, 0x74),
+        Opcode::BZ => (Some(ZERO), 0x75),
+        Opcode::BNZ => (Some(ZERO), 0x74),
+        Opcode::BN => (Some(NEGATIVE), 0x75),
+        Opcode::BNN => (Some(NEGATIVE), 0x74),
+        Opcode::BC => (Some(CARRY), 0x75),
+        Opcode::BNC => (Some(CARRY), 0x74),
+        Opcode::BV => (Some(OVERFLOW), 0x75),
+        _ => (Some(OVERFLOW), 0x74),
+    };
+    match flag {
+        None => code.extend_from_slice(&[0x80, 0x3e, 0x00]),
+        Some(flag) => code.extend_from_slice(&[0x80, 0x7a, flag, 0x00]),
+    }
+    code.extend_from_slice(&[jcc, 0x07]);
+    code.extend_from_slice(&[0x48, 0xc7, 0xc0]);
+    imm32(code, pc as i64 + 4);
+    code.push(0xc3);
+}
+
+// Flags after the arithmetic in eax. IMUL only sets C and V, so Z and N come
+// from a test eax, eax; the bitwise operations set none.
+fn arithmetic_flags(code: &mut Vec<u8>, op: Opcode) {
+    match op {
+        Opcode::ADD | Opcode::SUB | Opcode::ADDI | Opcode::SUBI => set_flags(code),
+        Opcode::MUL | Opcode::MULI => {
+            code.extend_from_slice(&[0x0f, 0x92, 0x42, CARRY, 0x0f, 0x90, 0x42, OVERFLOW]);
+            code.extend_from_slice(&[0x85, 0xc0, 0x0f, 0x94, 0x42, ZERO, 0x0f, 0x98, 0x42]);
+            code.push(NEGATIVE);
+        }
+        _ => (),
+    }
+}
+
+// the second opcode byte of the SETcc for a comparison
+fn setcc(op: Opcode) -> u8 {
+    match op {
+        Opcode::EQ | Opcode::EQI => 0x94,
+        Opcode::NEQ | Opcode::NEQI => 0x95,
+        Opcode::GT | Opcode::GTI => 0x9f,
+        Opcode::LT | Opcode::LTI => 0x9c,
+        Opcode::GTEQ | Opcode::GTEQI => 0x9d,
+        _ => 0x9e,
+    }
+}
+
+fn translate(block: &[(usize, Instruction)], end: usize) -> Vec<u8> {
+    let mut code = vec![];
+    for &(pc, instruction) in block {
+        let [a, b, c] = instruction.operands();
+        // branches are the only instructions not starting with a register
+        let x = match instruction.opcode().layout() {
+            Layout::Branch => 0,
+            _ => register(a),
+        };
+        match instruction.opcode() {
+            Opcode::LOAD => {
+                // mov dword [rdi + a], imm32
+                code.extend_from_slice(&[0xc7, 0x47, x]);
+                imm32(&mut code, u16::from_be_bytes([b, c]) as i64);
+            }
+            op @ (Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR) => {
+                let (y, output) = (register(b), register(c));
+                // mov eax, [rdi + x]; <op> eax, [rdi + y]; mov [rdi + output], eax
+                code.extend_from_slice(&[0x8b, 0x47, x]);
+                match op {
+                    Opcode::ADD => code.extend_from_slice(&[0x03, 0x47, y]),
+                    Opcode::SUB => code.extend_from_slice(&[0x2b, 0x47, y]),
+                    Opcode::AND => code.extend_from_slice(&[0x23, 0x47, y]),
+                    Opcode::OR => code.extend_from_slice(&[0x0b, 0x47, y]),
+                    Opcode::XOR => code.extend_from_slice(&[0x33, 0x47, y]),
+                    _ => code.extend_from_slice(&[0x0f, 0xaf, 0x47, y]),
+                }
+                arithmetic_flags(&mut code, op);
+                code.extend_from_slice(&[0x89, 0x47, output]);
+            }
+            op @ (Opcode::ADDI | Opcode::SUBI | Opcode::MULI) => {
+                let (output, immediate) = (register(b), c as i8 as i64);
+                // mov eax, [rdi + x]; <op> eax, imm32; mov [rdi + output], eax
+                code.extend_from_slice(&[0x8b, 0x47, x]);
+                match op {
+                    Opcode::ADDI => code.push(0x05),
+                    Opcode::SUBI => code.push(0x2d),
+                    _ => code.extend_from_slice(&[0x69, 0xc0]),
+                }
+                imm32(&mut code, immediate);
+                arithmetic_flags(&mut code, op);
+                code.extend_from_slice(&[0x89, 0x47, output]);
+            }
+            // add/sub dword [rdi + x], 1
+            Opcode::INC => {
+                code.extend_from_slice(&[0x83, 0x47, x, 0x01]);
+                set_flags(&mut code);
+            }
+            Opcode::DEC => {
+                code.extend_from_slice(&[0x83, 0x6f, x, 0x01]);
+                set_flags(&mut code);
+            }
+            op @ (Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTEQ
+            | Opcode::LTEQ) => {
+                let y = register(b);
+                // mov eax, [rdi + x]; cmp eax, [rdi + y]; set<cc> byte [rsi]
+                code.extend_from_slice(&[0x8b, 0x47, x, 0x3b, 0x47, y, 0x0f, setcc(op), 0x06]);
+                set_flags(&mut code);
+            }
+            op @ (Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::LTI
+            | Opcode::GTEQI
+            | Opcode::LTEQI) => {
+                // mov eax, [rdi + x]; cmp eax, imm32; set<cc> byte [rsi]
+                code.extend_from_slice(&[0x8b, 0x47, x, 0x3d]);
+                imm32(&mut code, i16::from_be_bytes([b, c]) as i64);
+                code.extend_from_slice(&[0x0f, setcc(op), 0x06]);
+                set_flags(&mut code);
+            }
+            Opcode::JMP => {
+                // movsxd rax, [rdi + a]; ret
+                code.extend_from_slice(&[0x48, 0x63, 0x47, x, 0xc3]);
+            }
+            Opcode::JMPF => {
+                // movsxd rax, [rdi + a]; add rax, pc + 2; ret
+                code.extend_from_slice(&[0x48, 0x63, 0x47, x, 0x48, 0x05]);
+                imm32(&mut code, pc as i64 + 2);
+                code.push(0xc3);
+            }
+            Opcode::JMPB => {
+                // movsxd rcx, [rdi + a]; mov rax, pc + 2; sub rax, rcx; ret
+                code.extend_from_slice(&[0x48, 0x63, 0x4f, x, 0x48, 0xc7, 0xc0]);
+                imm32(&mut code, pc as i64 + 2);
+                code.extend_from_slice(&[0x48, 0x29, 0xc8, 0xc3]);
+            }
+            op @ (Opcode::JEQ | Opcode::JNEQ) => {
+                // movsxd rax, [rdi + a]
+                code.extend_from_slice(&[0x48, 0x63, 0x47, x]);
+                conditional(&mut code, pc, op);
+            }
+            Opcode::BRA => {
+                // mov rax, target; ret
+                code.extend_from_slice(&[0x48, 0xc7, 0xc0]);
+                imm32(&mut code, branch_target(pc, a, b));
+                code.push(0xc3);
+            }
+            Opcode::LOOP => {
+                // sub dword [rdi + a], 1; mov rax, target; jnz over the fall
+                // through to pc + 4; ret
+                code.extend_from_slice(&[0x83, 0x6f, x, 0x01, 0x48, 0xc7, 0xc0]);
+                imm32(&mut code, branch_target(pc, b, c));
+                code.extend_from_slice(&[0x75, 0x07]);
+                return_pc(&mut code, pc + 4);
+            }
+            op if op.layout() == Layout::Branch => {
+                // mov rax, target
+                code.extend_from_slice(&[0x48, 0xc7, 0xc0]);
+                imm32(&mut code, branch_target(pc, a, b));
+                conditional(&mut code, pc, op);
+            }
+            op => unreachable!("{op:?} is not compiled"),
+        }
+    }
+    if block
+        .last()
+        .is_none_or(|(_, i)| !super::ends_block(i.opcode()))
+    {
+        return_pc(&mut code, end);
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let add = Instruction::decode(&[2, 1, 2, 3]);
+        let jeq = Instruction::decode(&[15, 4, 0, 0]);
+        let xor = Instruction::decode(&[19, 5, 5, 6]);
+        let subi = Instruction::decode(&[25, 1, 2, 0xff]);
+        let gti = Instruction::decode(&[29, 3, 0x01, 0x00]);
+        let bneq = Instruction::decode(&[38, 0xff, 0xf8, 0]);
+        let bc = Instruction::decode(&[43, 0, 8, 0]);
+        let lp = Instruction::decode(&[71, 2, 0xff, 0xfc]);
+        let flags = [
+            0x0f, 0x94, 0x42, 0, 0x0f, 0x98, 0x42, 1, 0x0f, 0x92, 0x42, 2, 0x0f, 0x90, 0x42, 3,
+        ];
+        assert_eq!(
+            translate(&[(0, add)], 4),
+            [
+                &[0x8b, 0x47, 4, 0x03, 0x47, 8][..],
+                &flags,
+                &[0x89, 0x47, 12, 0x48, 0xc7, 0xc0, 4, 0, 0, 0, 0xc3]
+            ]
+            .concat()
+        );
+        assert_eq!(
+            translate(&[(0, xor)], 4)[..9],
+            [0x8b, 0x47, 20, 0x33, 0x47, 20, 0x89, 0x47, 24]
+        );
+        assert_eq!(
+            translate(&[(0, subi), (4, gti)], 8)[..55],
+            [
+                &[0x8b, 0x47, 4, 0x2d, 0xff, 0xff, 0xff, 0xff][..],
+                &flags,
+                &[0x89, 0x47, 8, 0x8b, 0x47, 12, 0x3d, 0x00, 0x01, 0x00, 0x00, 0x0f, 0x9f, 0x06],
+                &flags,
+                &[0x48]
+            ]
+            .concat()
+        );
+        assert_eq!(
+            translate(&[(8, jeq)], 12),
+            [
+                0x48, 0x63, 0x47, 16, 0x80, 0x3e, 0x00, 0x75, 0x07, 0x48, 0xc7, 0xc0, 12, 0, 0, 0,
+                0xc3
+            ]
+        );
+        assert_eq!(
+            translate(&[(8, bneq)], 12),
+            [
+                0x48, 0xc7, 0xc0, 0, 0, 0, 0, 0x80, 0x3e, 0x00, 0x74, 0x07, 0x48, 0xc7, 0xc0, 12,
+                0, 0, 0, 0xc3
+            ]
+        );
+        assert_eq!(
+            translate(&[(8, bc)], 12),
+            [
+                0x48, 0xc7, 0xc0, 16, 0, 0, 0, 0x80, 0x7a, 2, 0x00, 0x75, 0x07, 0x48, 0xc7, 0xc0,
+                12, 0, 0, 0, 0xc3
+            ]
+        );
+        assert_eq!(
+            translate(&[(8, lp)], 12),
+            [
+                0x83, 0x6f, 8, 0x01, 0x48, 0xc7, 0xc0, 4, 0, 0, 0, 0x75, 0x07, 0x48, 0xc7, 0xc0,
+                12, 0, 0, 0, 0xc3
+            ]
+        );
+    }
+}
@@ -0,0 +1,1723 @@
+mod breakpoints;
+mod closures;
+#[cfg(test)]
+mod differential;
+mod execute;
+mod fault;
+mod flags;
+mod fusion;
+mod history;
+mod jit;
+mod observer;
+mod snapshot;
+mod trace;
+
+pub use breakpoints::{BreakpointHit, Watchpoint};
+pub use fault::Fault;
+pub use flags::Flags;
+pub use observer::ExecutionObserver;
+pub use snapshot::{Snapshot, SnapshotError};
+pub use trace::{Trace, TraceEntry};
+
+use crate::instructions::{Instruction, Opcode};
+use breakpoints::Breakpoints;
+use closures::Compiled;
+use fusion::Superinstruction;
+use jit::Jit;
+use history::{History, Undo};
+use std::any::Any;
+use std::mem;
+use std::slice::Iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Why the VM handed control back to its host.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Outcome {
+    /// a HLT instruction was executed
+    Halted,
+    /// the program counter ran past the end of the program
+    Finished,
+    /// the program did something illegal. The program counter is left on the
+    /// faulting instruction, except for illegal opcodes, which it steps past
+    Fault(Fault),
+    /// the next instruction costs more than the fuel left. Nothing was executed,
+    /// so refueling and calling `run` again resumes right where it stopped
+    OutOfFuel,
+    /// someone called `interrupt` on one of the VM's `Interrupt` handles.
+    /// The VM stops between instructions, so its state is intact
+    Interrupted,
+    /// a breakpoint or watchpoint was hit
+    Breakpoint(BreakpointHit),
+}
+
+/// How the VM runs programs when nothing is watching it run. Tracing,
+/// history, watchpoints and observers always go through the interpreter.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Backend {
+    /// decodes and dispatches one instruction at a time
+    #[default]
+    Interpreter,
+    /// compiles each basic block into a chain of closures at load time and
+    /// runs blocks in one go. Interrupts are noticed between blocks
+    Closures,
+    /// interprets, but translates blocks that run often to native code and
+    /// runs those in one go. Only on x86-64 Linux; elsewhere it just
+    /// interprets
+    Jit,
+}
+
+/// Cloneable handle for stopping a running VM from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn new() -> Interrupt {
+        Interrupt(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Asks the VM to stop before its next instruction. If it is not running,
+    /// the request stays pending until it next runs.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Drops a pending request.
+    pub fn clear(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+
+    fn take(&self) -> bool {
+        self.is_pending() && self.0.swap(false, Ordering::AcqRel)
+    }
+}
+
+pub struct VM {
+    registers: [i32; 32],
+    float_registers: [f64; 32],
+    // what LOADF loads from
+    constants: Vec<f64>,
+    remainder: i32,
+    eq_flag: bool,
+    flags: Flags,
+    stdout: usize,
+    heap: Vec<u8>,
+    // what PRTS printed, until it is taken
+    output: String,
+    pc: usize,
+    program: Vec<u8>,
+    fuel: Option<u64>,
+    costs: [u64; 256],
+    interrupt: Interrupt,
+    trace: Option<Trace>,
+    history: Option<History>,
+    breakpoints: Breakpoints,
+    observers: Vec<Box<dyn ExecutionObserver>>,
+    decoded: Vec<Instruction>,
+    use_decoded: bool,
+    fused: Vec<Option<Superinstruction>>,
+    use_fusion: bool,
+    backend: Backend,
+    compiled: Vec<Compiled>,
+    jit: Jit,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        VM {
+            registers: [0; 32],
+            float_registers: [0.0; 32],
+            constants: vec![],
+            pc: 0,
+            program: vec![],
+            remainder: 0,
+            eq_flag: false,
+            flags: Flags::default(),
+            stdout: 0,
+            heap: vec![],
+            output: String::new(),
+            fuel: None,
+            costs: [1; 256],
+            interrupt: Interrupt::new(),
+            trace: None,
+            history: None,
+            breakpoints: Breakpoints::default(),
+            observers: vec![],
+            decoded: vec![],
+            use_decoded: true,
+            fused: vec![],
+            use_fusion: true,
+            backend: Backend::Interpreter,
+            compiled: vec![],
+            jit: Jit::default(),
+        }
+    }
+
+    // quizás estaría bueno ocultar estas implementaciones detras de un trait Stdout
+    pub fn stdout(&self) -> i32 {
+        if self.stdout == 33 {
+            self.eq_flag as i32
+        } else {
+            self.registers[self.stdout]
+        }
+    }
+
+    pub fn sdtout_string(&self) -> String {
+        if self.stdout == 33 {
+            self.eq_flag.to_string()
+        } else {
+            self.registers[self.stdout].to_string()
+        }
+    }
+
+    fn is_not_done(&self) -> bool {
+        self.pc < self.program.len()
+    }
+
+    // charges the next instruction against the budget, if there is one
+    fn burn_fuel(&mut self) -> bool {
+        let cost = self.costs[self.program[self.pc] as usize];
+        match self.fuel {
+            Some(fuel) if fuel < cost => false,
+            Some(fuel) => {
+                self.fuel = Some(fuel - cost);
+                true
+            }
+            None => true,
+        }
+    }
+
+    // `single` runs just the instruction at the pc, rather than the
+    // superinstruction or compiled block it may start
+    fn step(&mut self, single: bool) -> Option<Outcome> {
+        if !self.is_not_done() {
+            Some(Outcome::Finished)
+        } else if self.interrupt.take() {
+            Some(Outcome::Interrupted)
+        } else if let Some(hit) = self.breakpoints.check_address(self.pc) {
+            Some(Outcome::Breakpoint(hit))
+        } else if !self.burn_fuel() {
+            // getting here on a breakpoint means we were resuming from it
+            if self.breakpoints.addresses().contains(&self.pc) {
+                self.breakpoints.resume_from(self.pc);
+            }
+            Some(Outcome::OutOfFuel)
+        } else if self.is_instrumented() {
+            self.execute_recorded()
+        } else if single {
+            self.execute(self.pc, self.fetch(self.pc))
+        } else {
+            self.execute_next()
+        }
+    }
+
+    fn is_instrumented(&self) -> bool {
+        self.trace.is_some()
+            || self.history.is_some()
+            || self.breakpoints.is_watching()
+            || !self.observers.is_empty()
+    }
+
+    fn notify(&mut self, f: impl Fn(&mut dyn ExecutionObserver, &VM)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = mem::take(&mut self.observers);
+        observers
+            .iter_mut()
+            .for_each(|observer| f(observer.as_mut(), self));
+        self.observers = observers;
+    }
+
+    // same as execute, but feeding the trace, the undo log, watchpoints
+    // and observers
+    fn execute_recorded(&mut self) -> Option<Outcome> {
+        let pc = self.pc;
+        let instruction = self.fetch(pc);
+        let eq_flag = self.eq_flag;
+        let flags = self.flags;
+        let watched_heap = self.breakpoints.watched_heap(&self.heap);
+        self.breakpoints.begin(pc);
+        self.notify(|observer, vm| observer.before_instruction(vm, pc, instruction));
+        if let Some(trace) = &mut self.trace {
+            trace.begin(pc, instruction);
+        }
+        if let Some(history) = &mut self.history {
+            history.push(Undo {
+                pc,
+                stdout: self.stdout,
+                eq_flag: self.eq_flag,
+                flags: self.flags,
+                remainder: self.remainder,
+                heap_len: self.heap.len(),
+                heap_tail: vec![],
+                output_len: self.output.len(),
+                registers: vec![],
+                float_registers: vec![],
+            });
+        }
+        let outcome = self.execute(pc, instruction);
+        match outcome {
+            Some(Outcome::Fault(fault)) => {
+                self.notify(|observer, vm| observer.on_fault(vm, pc, fault))
+            }
+            _ => self.notify(|observer, vm| observer.after_instruction(vm, pc, instruction)),
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.record_eq_flag(eq_flag, self.eq_flag);
+            trace.record_flags(flags, self.flags);
+        }
+        let hit = self
+            .breakpoints
+            .finish(watched_heap, &self.heap, (eq_flag, self.eq_flag));
+        match (outcome, hit) {
+            (None, Some(hit)) => Some(Outcome::Breakpoint(hit)),
+            _ => outcome,
+        }
+    }
+
+    /// Runs until the program halts, falls off its end, runs out of fuel,
+    /// is interrupted or hits a breakpoint.
+    pub fn run(&mut self) -> Outcome {
+        self.refresh_decoded();
+        loop {
+            if let Some(outcome) = self.step(false) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Like `run`, but stops after spending `budget` units of fuel.
+    /// Whatever is left afterwards can be read with `fuel`, and later runs keep
+    /// spending it until `set_fuel(None)` lifts the limit.
+    pub fn run_with_budget(&mut self, budget: u64) -> Outcome {
+        self.fuel = Some(budget);
+        self.run()
+    }
+
+    /// Executes a single instruction. Returns `None` if the VM can keep going.
+    pub fn run_once(&mut self) -> Option<Outcome> {
+        self.refresh_decoded();
+        self.step(true)
+    }
+
+    /// Whether instructions are dispatched from a copy of the program decoded
+    /// ahead of time (the default) or decoded from bytes on every step. Both
+    /// behave the same, this only exists to measure and debug the former.
+    pub fn set_predecoding(&mut self, enabled: bool) {
+        self.use_decoded = enabled;
+    }
+
+    /// Whether common instruction pairs, like a comparison and the JEQ after
+    /// it, run as a single superinstruction (the default). Needs predecoding,
+    /// and is skipped while tracing, recording history, watching or observing.
+    /// Turning it off is only useful for debugging the VM itself.
+    pub fn set_fusion(&mut self, enabled: bool) {
+        self.use_fusion = enabled;
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Switches backends. Programs are compiled on the next run.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.discard_compiled();
+    }
+
+    /// A handle that interrupts this VM. Every clone controls the same VM.
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn eq_flag(&self) -> bool {
+        self.eq_flag
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// Fuel left, or `None` when execution is unmetered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Sets the fuel left. `None` lifts the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// How much fuel an instruction with this opcode costs. Defaults to 1.
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[opcode as usize]
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+        self.discard_compiled();
+    }
+
+    pub fn program(&self) -> Iter<'_, u8> {
+        self.program.iter()
+    }
+
+    pub fn registers(&self) -> Iter<'_, i32> {
+        self.registers.iter()
+    }
+
+    pub fn float_registers(&self) -> Iter<'_, f64> {
+        self.float_registers.iter()
+    }
+
+    pub fn constants(&self) -> &[f64] {
+        &self.constants
+    }
+
+    /// Sets the constant pool LOADF loads from, which is what the assembler
+    /// collects from a program's float literals.
+    pub fn set_constants(&mut self, constants: Vec<f64>) {
+        self.constants = constants;
+    }
+
+    /// Replaces the heap, usually with a program's data segment so its
+    /// strings are where the assembler put them.
+    pub fn set_heap(&mut self, heap: Vec<u8>) {
+        self.heap = heap;
+    }
+
+    /// What the program printed since the output was last taken.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Starts recording every executed instruction, dropping any earlier trace.
+    pub fn start_trace(&mut self) {
+        self.trace = Some(Trace::new());
+    }
+
+    /// Stops recording and hands over what was recorded.
+    pub fn stop_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// Starts keeping an undo log of the last `limit` instructions so they
+    /// can be stepped back over. Any earlier log is dropped.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// How many instructions can currently be stepped back over.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    /// Undoes the last executed instruction. Returns false when there is
+    /// nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(History::pop) {
+            Some(undo) => undo,
+            None => return false,
+        };
+        undo.registers
+            .iter()
+            .rev()
+            .for_each(|&(register, old)| self.registers[register] = old);
+        undo.float_registers
+            .iter()
+            .rev()
+            .for_each(|&(register, old)| self.float_registers[register] = old);
+        self.pc = undo.pc;
+        self.stdout = undo.stdout;
+        self.eq_flag = undo.eq_flag;
+        self.flags = undo.flags;
+        self.remainder = undo.remainder;
+        self.heap.truncate(undo.heap_len);
+        self.heap.extend(undo.heap_tail);
+        self.output.truncate(undo.output_len);
+        true
+    }
+
+    /// Steps back until `stop` holds for the VM, e.g. when the pc sits on a
+    /// breakpoint, or the history runs out. Returns whether `stop` was reached.
+    pub fn run_back_until(&mut self, stop: impl Fn(&VM) -> bool) -> bool {
+        while self.step_back() {
+            if stop(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Pauses execution right before the instruction at `address` runs.
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.addresses_mut().insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.addresses_mut().remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.addresses().iter().copied()
+    }
+
+    /// Pauses execution right after an instruction triggers `watchpoint`.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.breakpoints.watchpoints_mut().push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let watchpoints = self.breakpoints.watchpoints_mut();
+        let before = watchpoints.len();
+        watchpoints.retain(|w| *w != watchpoint);
+        watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.breakpoints.watchpoints()
+    }
+
+    /// Steps back to the previous breakpoint, as far as the history allows.
+    /// Running forward from there executes the instruction at the breakpoint.
+    pub fn reverse_continue(&mut self) -> Option<BreakpointHit> {
+        let addresses = self.breakpoints.addresses().clone();
+        if self.run_back_until(|vm| addresses.contains(&vm.pc)) {
+            self.breakpoints.resume_from(self.pc);
+            Some(BreakpointHit::Address(self.pc))
+        } else {
+            None
+        }
+    }
+
+    /// Attaches an observer that sees every instruction from now on.
+    /// Without observers, execution does not pay for the hooks.
+    pub fn attach_observer<T: ExecutionObserver>(&mut self, observer: T) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// The first attached observer of type `T`.
+    pub fn observer<T: ExecutionObserver>(&self) -> Option<&T> {
+        self.observers
+            .iter()
+            .find_map(|observer| (observer.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn observer_mut<T: ExecutionObserver>(&mut self) -> Option<&mut T> {
+        self.observers
+            .iter_mut()
+            .find_map(|observer| (observer.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Detaches the first attached observer of type `T` and hands it back.
+    pub fn detach_observer<T: ExecutionObserver>(&mut self) -> Option<T> {
+        let index = self
+            .observers
+            .iter()
+            .position(|observer| (observer.as_ref() as &dyn Any).is::<T>())?;
+        let observer: Box<dyn Any> = self.observers.remove(index);
+        observer.downcast().ok().map(|observer| *observer)
+    }
+
+    /// Captures the VM state so it can be saved and later `restore`d.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            remainder: self.remainder,
+            eq_flag: self.eq_flag,
+            flags: self.flags,
+            stdout: self.stdout,
+            heap: self.heap.clone(),
+            pc: self.pc,
+            program: self.program.clone(),
+            float_registers: self.float_registers,
+            constants: self.constants.clone(),
+        }
+    }
+
+    /// Puts the VM back in the state captured by `snapshot`. Fuel, costs and
+    /// interrupt handles are left as they are. Output not taken yet is
+    /// dropped, since it belongs to whatever ran before.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers;
+        self.remainder = snapshot.remainder;
+        self.eq_flag = snapshot.eq_flag;
+        self.flags = snapshot.flags;
+        self.stdout = snapshot.stdout;
+        self.heap = snapshot.heap.clone();
+        self.pc = snapshot.pc;
+        self.program = snapshot.program.clone();
+        self.float_registers = snapshot.float_registers;
+        self.constants = snapshot.constants.clone();
+        self.output.clear();
+        self.decoded.clear();
+        self.fused.clear();
+        self.discard_compiled();
+        if let Some(history) = &mut self.history {
+            *history = History::new(history.limit());
+        }
+    }
+}
+
+// this is just another name for the "extend" trait
+pub trait Stdin<A> {
+    fn stdin<T: IntoIterator<Item = A>>(&mut self, program: T);
+}
+
+impl Stdin<u8> for VM {
+    fn stdin<T: IntoIterator<Item = u8>>(&mut self, iter: T) {
+        self.program.extend(iter);
+    }
+}
+
+impl Stdin<[u8; 4]> for VM {
+    fn stdin<T: IntoIterator<Item = [u8; 4]>>(&mut self, iter: T) {
+        iter.into_iter()
+            .for_each(|instruction| self.stdin(instruction));
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_test_vm() -> VM {
+        VM::new()
+    }
+
+    #[test]
+    fn test_create_vm() {
+        let vm = VM::new();
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_hlt() {
+        let mut vm = new_test_vm();
+        let bytes = vec![0, 0, 0, 0];
+        vm.program = bytes;
+        vm.run_once();
+        assert_eq!(vm.pc, 1);
+    }
+    #[test]
+    fn test_ilgl() {
+        let mut vm = new_test_vm();
+        let bytes = vec![200, 0, 0, 0];
+        vm.program = bytes;
+        vm.run_once();
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_load_to_0_ok() {
+        let mut vm = new_test_vm();
+        let bytes = vec![1, 0, 1, 244];
+        vm.program = bytes;
+        vm.run_once();
+        assert_eq!(vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_load_to_0_fail() {
+        let mut vm = new_test_vm();
+        let bytes = vec![0, 0, 1, 244];
+        vm.program = bytes;
+        vm.run_once();
+        assert_ne!(vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut vm = new_test_vm();
+        let bytes = vec![
+            1, 0, 1, 244, // load 500 to register 0
+            1, 1, 1, 244, // load 500 to register 1
+            2, 0, 1, 2, // add load the sum of r0 and r1 to r2
+        ];
+        vm.program = bytes;
+        vm.run();
+        println!("registers = {:?}", vm.registers);
+        assert_eq!(vm.registers[2], 1000);
+    }
+
+    #[test]
+    fn test_sub() {
+        let mut vm = new_test_vm();
+        let bytes = vec![
+            1, 0, 1, 244, // load 500 to register 0
+            1, 1, 0, 244, // load 244 to register 1
+            3, 0, 1, 2, // load the sum of r0 and r1 to r2
+        ];
+        vm.program = bytes;
+        vm.run();
+        println!("registers = {:?}", vm.registers);
+        assert_eq!(vm.registers[2], 256);
+    }
+
+    #[test]
+    fn test_mul() {
+        let mut vm = VM::new();
+        let bytes = vec![
+            1, 0, 0, 2, // load 2 to register 0
+            1, 1, 0, 255, // load 255 to register 1
+            4, 0, 1, 2, // load the product of r0 and r1 to r2
+        ];
+        vm.program = bytes;
+        vm.run();
+        println!("registers = {:?}", vm.registers);
+        assert_eq!(vm.registers[2], 255 * 2);
+    }
+
+    #[test]
+    fn test_div() {
+        let mut vm = new_test_vm();
+        let bytes = vec![
+            1, 0, 0, 244, // load 244 to register 0
+            1, 1, 0, 2, // load 2 to register 1
+            5, 0, 1, 2, // load the quotient of r0 and r1 to r2
+        ];
+        vm.program = bytes;
+        vm.run();
+        println!("registers = {:?}", vm.registers);
+        assert_eq!(vm.registers[2], 122);
+    }
+    #[test]
+    fn test_div_with_remainder() {
+        let mut vm = new_test_vm();
+        let bytes = vec![
+            1, 0, 0, 245, // load 245 to register 0
+            1, 1, 0, 2, // load 2 to register 1
+            5, 0, 1, 2, // load the quotient of r0 and r1 to r2
+        ];
+        vm.program = bytes;
+        vm.run();
+        println!("registers = {:?}", vm.registers);
+        assert_eq!(vm.registers[2], 122);
+        assert_eq!(vm.remainder, 1);
+    }
+
+    #[test]
+    fn test_unsigned() {
+        let run = |program: Vec<u8>, x: i32, y: i32| {
+            let mut vm = new_test_vm();
+            vm.registers[0] = x;
+            vm.registers[1] = y;
+            vm.program = program;
+            vm.run();
+            vm
+        };
+        // DIV's remainder has the sign of the dividend, REM reads it back
+        let vm = run(vec![5, 0, 1, 2, 52, 3, 0, 0], -7, 2);
+        assert_eq!((vm.registers[2], vm.registers[3]), (-3, -1));
+        let vm = run(vec![5, 0, 1, 2, 52, 3, 0, 0], i32::MIN, -1);
+        assert_eq!((vm.registers[2], vm.registers[3]), (i32::MIN, 0));
+        let vm = run(vec![47, 0, 1, 2, 52, 3, 0, 0], -7, 2);
+        assert_eq!((vm.registers[2], vm.registers[3]), (i32::MAX - 3, 1));
+        let vm = run(vec![47, 0, 1, 2, 52, 3, 0, 0], -1, i32::MIN);
+        assert_eq!((vm.registers[2], vm.registers[3]), (1, i32::MAX));
+        assert_eq!(run(vec![47, 0, 1, 2], 1, 0).pc, 0);
+
+        let compare = |opcode: u8, x: i32, y: i32| run(vec![opcode, 0, 1, 0], x, y).eq_flag;
+        assert!(compare(48, -1, 1));
+        assert!(!compare(11, -1, 1));
+        assert!(compare(49, 0, i32::MIN));
+        assert!(compare(50, -1, -1));
+        assert!(!compare(51, -1, 0));
+    }
+
+    #[test]
+    fn test_wide_arithmetic() {
+        // ($1:$0) + ($3:$2) into ($5:$4), and ($1:$0) - ($3:$2) into ($7:$6)
+        let program = vec![
+            2, 0, 2, 4, // add $0 $2 $4
+            55, 1, 3, 5, // adc $1 $3 $5
+            3, 0, 2, 6, // sub $0 $2 $6
+            56, 1, 3, 7, // sbc $1 $3 $7
+        ];
+        let run = |x: i64, y: i64| {
+            let mut vm = new_test_vm();
+            let words = |v: i64| [v as i32, (v >> 32) as i32];
+            vm.registers[..2].copy_from_slice(&words(x));
+            vm.registers[2..4].copy_from_slice(&words(y));
+            vm.program = program.clone();
+            vm.run();
+            let pair =
+                |low: usize| (vm.registers[low + 1] as i64) << 32 | vm.registers[low] as u32 as i64;
+            (pair(4), pair(6), vm.flags.overflow)
+        };
+        assert_eq!(run(0xffff_ffff, 1), (0x1_0000_0000, 0xffff_fffe, false));
+        assert_eq!(run(0x1_0000_0000, 1), (0x1_0000_0001, 0xffff_ffff, false));
+        assert_eq!(run(-1, -1), (-2, 0, false));
+        assert_eq!(run(i64::MIN, 1), (i64::MIN + 1, i64::MAX, true));
+        assert_eq!(run(i64::MAX, 1).0, i64::MIN);
+
+        let multiply = |opcode: u8, x: i32, y: i32| {
+            let mut vm = new_test_vm();
+            vm.registers[0] = x;
+            vm.registers[1] = y;
+            vm.program = vec![opcode, 0, 1, 2];
+            vm.run();
+            (vm.registers[3] as i64) << 32 | vm.registers[2] as u32 as i64
+        };
+        assert_eq!(multiply(53, i32::MIN, i32::MIN), 1 << 62);
+        assert_eq!(multiply(53, i32::MAX, -1), -(i32::MAX as i64));
+        assert_eq!(multiply(53, -1, -1), 1);
+        assert_eq!(multiply(54, -1, -1), 0xffff_fffe_0000_0001_u64 as i64);
+        assert_eq!(multiply(54, -1, 2), 0x1_ffff_fffe);
+
+        // the pair can't start at the last register
+        let mut vm = new_test_vm();
+        vm.program = vec![53, 0, 1, 31];
+        assert_eq!(vm.run(), Outcome::Fault(Fault::InvalidRegister(32)));
+        assert_eq!(vm.registers[31], 0);
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 0b1100;
+        vm.registers[1] = 0b1010;
+        vm.program = vec![
+            17, 0, 1, 2, // and
+            18, 0, 1, 3, // or
+            19, 0, 1, 4, // xor
+            20, 0, 5, 0, // not
+        ];
+        vm.run();
+        assert_eq!(vm.registers[2..6], [0b1000, 0b1110, 0b0110, !0b1100]);
+        assert_eq!(vm.stdout(), -13);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let run = |opcode: u8, x: i32, count: i32| {
+            let mut vm = new_test_vm();
+            vm.registers[0] = x;
+            vm.registers[1] = count;
+            vm.program = vec![opcode, 0, 1, 2];
+            vm.run();
+            vm.registers[2]
+        };
+        assert_eq!(run(21, 3, 4), 48);
+        assert_eq!(run(21, 1, 31), i32::MIN);
+        assert_eq!(run(22, -16, 2), 0x3fff_fffc);
+        assert_eq!(run(23, -16, 2), -4);
+        assert_eq!(run(23, 16, 2), 4);
+        // only the low 5 bits of the count matter
+        assert_eq!(run(21, 3, 32), 3);
+        assert_eq!(run(22, -1, 33), i32::MAX);
+        assert_eq!(run(23, -16, -30), -4);
+    }
+
+    #[test]
+    fn test_immediates() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 10;
+        vm.program = vec![
+            24, 0, 1, 0xfd, // addi $0 #-3 $1
+            25, 0, 2, 20, // subi $0 #20 $2
+            26, 0, 3, 0x80, // muli $0 #-128 $3
+            33, 4, 0, 0, // inc $4
+            34, 5, 0, 0, // dec $5
+        ];
+        vm.run();
+        assert_eq!(vm.registers[..6], [10, 7, -10, -1280, 1, -1]);
+        assert_eq!(vm.stdout(), -1);
+
+        let compare = |opcode: u8, x: i32, immediate: i16| {
+            let mut vm = new_test_vm();
+            vm.registers[0] = x;
+            let [high, low] = immediate.to_be_bytes();
+            vm.program = vec![opcode, 0, high, low];
+            vm.run();
+            vm.eq_flag
+        };
+        assert!(compare(27, -300, -300));
+        assert!(!compare(28, 5, 5));
+        assert!(compare(29, 0, -1));
+        assert!(!compare(30, 32767, i16::MAX));
+        assert!(compare(31, -32768, i16::MIN));
+        assert!(compare(32, 3, 4));
+    }
+
+    #[test]
+    fn test_jump() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 3;
+        vm.program = vec![6, 0, 0, 0];
+        vm.run_once();
+        println!("registers = {:?}", vm.registers);
+        println!("program_counter = {:?}", vm.pc);
+        assert_eq!(vm.pc, 3);
+    }
+
+    #[test]
+    fn test_jump_forward() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 4;
+        vm.program = vec![7, 0, 0, 0];
+        vm.run_once();
+        println!("registers = {:?}", vm.registers);
+        println!("program_counter = {:?}", vm.pc);
+        assert_eq!(vm.pc, 6);
+    }
+
+    #[test]
+    fn test_jump_back() {
+        let mut vm = new_test_vm();
+        vm.registers[1] = 2;
+
+        vm.program = vec![8, 1, 0, 0];
+        vm.run_once();
+        vm.run_once();
+        println!("registers = {:?}", vm.registers);
+        println!("program_counter = {:?}", vm.pc);
+        assert_eq!(vm.pc, 0);
+    }
+
+    #[test]
+    fn test_jump_forward_then_back() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 2;
+        vm.registers[1] = 4;
+
+        vm.program = vec![
+            7, 0, 0, 0, // jump forward as many steps as the value of r0
+            8, 1, 0, 0, // jump backward as many steps as the value of r1
+        ];
+        vm.run_once();
+        vm.run_once();
+        println!("registers = {:?}", vm.registers);
+        println!("program_counter = {:?}", vm.pc);
+        assert_eq!(vm.pc, 2);
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn test_eq() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 10;
+        vm.registers[1] = 10;
+        vm.registers[2] = 5;
+        vm.program = vec![
+            9, 0, 1, 0, // compare r0 and r1 for equality
+            9, 0, 2, 0, // compare r0 and r2 for equality
+        ];
+        println!("registers = {:?}", vm.registers);
+        vm.run_once();
+        println!("equality flag = {}", vm.eq_flag);
+        assert_eq!(vm.eq_flag, true);
+        vm.run_once();
+        println!("equality flag = {}", vm.eq_flag);
+        assert_eq!(vm.eq_flag, false);
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn test_neq() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 10;
+        vm.registers[1] = 10;
+        vm.registers[2] = 5;
+        vm.program = vec![
+            10, 0, 1, 0, // compare r0 and r1 for inequality
+            10, 0, 2, 0, // compare r0 and r2 for inequality
+        ];
+        println!("registers = {:?}", vm.registers);
+        vm.run_once();
+        println!("equality flag = {}", vm.eq_flag);
+        assert_eq!(vm.eq_flag, false);
+        vm.run_once();
+        println!("equality flag = {}", vm.eq_flag);
+        assert_eq!(vm.eq_flag, true);
+    }
+
+    #[test]
+    fn test_jeq() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 7;
+        vm.eq_flag = true;
+        vm.program = vec![15, 0, 0, 0];
+        println!("registers = {:?}", vm.registers);
+        println!("program_counter before = {}", vm.pc);
+        vm.run_once();
+        println!("equality flag = {}", vm.eq_flag);
+        println!("program_counter after = {}", vm.pc);
+        assert_eq!(vm.pc, 7);
+    }
+
+    #[test]
+    fn test_jeq_not_taken() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 7;
+        vm.program = vec![
+            15, 0, 0, 0, // jump to r0 if equal
+            1, 1, 0, 1, // load 1 to register 1
+        ];
+        vm.run();
+        assert_eq!(vm.pc, 8);
+        assert_eq!(vm.registers[1], 1);
+    }
+
+    #[test]
+    fn test_jneq() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 7;
+        vm.program = vec![35, 0, 0, 0];
+        vm.run_once();
+        assert_eq!(vm.pc, 7);
+        vm.pc = 0;
+        vm.eq_flag = true;
+        vm.run_once();
+        assert_eq!(vm.pc, 4);
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            36, 0, 8, 0, // bra +8
+            0, 0, 0, 0, // hlt, skipped
+            37, 0, 100, 0, // beq +100, not taken
+            38, 0xff, 0xfc, 0, // bneq -4, taken
+        ];
+        vm.set_fuel(Some(5));
+        assert_eq!(vm.run(), Outcome::OutOfFuel);
+        assert_eq!(vm.pc, 8);
+        vm.set_fuel(None);
+        vm.eq_flag = true;
+        vm.pc = 8;
+        vm.run_once();
+        assert_eq!(vm.pc, 108);
+        vm.program = vec![38, 0xff, 0xfc, 0];
+        vm.pc = 0;
+        vm.eq_flag = false;
+        assert_eq!(vm.run_once(), Some(Outcome::Fault(Fault::InvalidJump(-4))));
+        assert_eq!(vm.pc, 0);
+    }
+
+    #[test]
+    fn test_jump_table() {
+        let run = |index: i32| {
+            let mut vm = new_test_vm();
+            vm.registers[0] = index;
+            vm.program = vec![
+                70, 0, 0, 2, // jtable $0 #2
+                36, 0, 16, 0, // bra #16
+                36, 0, 20, 0, // bra #20
+                1, 1, 0, 9, // load 9 to $1, the default
+                0, 0, 0, 0, // hlt
+                1, 1, 0, 1, // load 1 to $1
+                0, 0, 0, 0, // hlt
+                1, 1, 0, 2, // load 2 to $1
+            ];
+            vm.run();
+            vm.registers[1]
+        };
+        assert_eq!([run(0), run(1), run(2), run(-1), run(i32::MIN)], [1, 2, 9, 9, 9]);
+    }
+
+    #[test]
+    fn test_loop() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 4, // load 4 to $0
+            2, 0, 1, 1, // add $0 to $1
+            71, 0, 0xff, 0xfc, // loop $0 #-4
+        ];
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!((vm.registers[0], vm.registers[1]), (0, 10));
+        assert_eq!(vm.stdout(), 0);
+        // the counter isn't touched when the branch faults
+        let mut vm = new_test_vm();
+        vm.registers[0] = 3;
+        vm.flags.carry = true;
+        vm.program = vec![71, 0, 0xff, 0xfc];
+        assert_eq!(vm.run_once(), Some(Outcome::Fault(Fault::InvalidJump(-4))));
+        assert_eq!((vm.pc, vm.registers[0]), (0, 3));
+        vm.registers[0] = 1;
+        assert_eq!(vm.run_once(), None);
+        assert_eq!((vm.pc, vm.registers[0]), (4, 0));
+        assert_eq!(vm.flags.to_string(), "--C-");
+        // a counter of 0 isn't done, it wraps around and goes on for 2^32 passes
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            2, 0, 1, 1, // add $0 to $1
+            71, 0, 0xff, 0xfc, // loop $0 #-4
+        ];
+        assert_eq!(vm.run_with_budget(100), Outcome::OutOfFuel);
+        assert_eq!(vm.registers[0], -50);
+    }
+
+    #[test]
+    fn test_flags() {
+        let run = |opcode: u8, x: i32, y: i32| {
+            let mut vm = new_test_vm();
+            vm.registers[0] = x;
+            vm.registers[1] = y;
+            vm.program = vec![opcode, 0, 1, 2];
+            vm.run();
+            (vm.registers[2], vm.flags.to_string())
+        };
+        assert_eq!(run(2, i32::MAX, 1), (i32::MIN, "-N-V".to_string()));
+        assert_eq!(run(2, -1, 1), (0, "Z-C-".to_string()));
+        assert_eq!(run(3, 1, 2), (-1, "-NC-".to_string()));
+        assert_eq!(run(4, 1 << 16, 1 << 16), (0, "Z-CV".to_string()));
+        assert_eq!(run(5, i32::MIN, -1), (i32::MIN, "-N-V".to_string()));
+        // comparisons set them like a subtraction, bitwise operations leave them
+        assert_eq!(run(12, 3, 3).1, "Z---");
+        assert_eq!(run(17, -1, -1), (-1, "----".to_string()));
+
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            34, 0, 0, 0, // dec $0
+            24, 0, 1, 1, // addi $0 #1 $1
+        ];
+        vm.enable_history(4);
+        vm.run_once();
+        assert_eq!(vm.flags.to_string(), "-NC-");
+        vm.run_once();
+        assert_eq!(vm.flags.to_string(), "Z-C-");
+        vm.step_back();
+        assert_eq!(vm.flags.to_string(), "-NC-");
+    }
+
+    #[test]
+    fn test_flag_branches() {
+        let taken = |opcode: u8, flags: u8| {
+            let mut vm = new_test_vm();
+            vm.flags = Flags::from_bits(flags).unwrap();
+            vm.program = vec![opcode, 0, 12, 0];
+            vm.run_once();
+            vm.pc == 12
+        };
+        for (i, opcode) in (39..47).enumerate() {
+            let flag = 1 << (i / 2);
+            // the even ones branch when their flag is set, the odd ones when clear
+            assert_eq!(taken(opcode, flag), i % 2 == 0, "{opcode}");
+            assert_eq!(taken(opcode, 0xf ^ flag), i % 2 == 1, "{opcode}");
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        // "hi" at 0, "there" at 3, a bad byte at 9 and no NUL after 11
+        let heap = b"hi\0there\0\xff\0no end".to_vec();
+        let mut vm = new_test_vm();
+        vm.set_heap(heap.clone());
+        vm.registers[1] = 3;
+        vm.program = vec![
+            72, 0, 0, 0, // print $0
+            72, 1, 0, 0, // print $1
+            74, 0, 1, 2, // $2 = $0 + $1
+            75, 2, 3, 0, // $3 = length of $2
+            73, 2, 2, 0, // $2 == $2
+        ];
+        vm.enable_history(8);
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.output(), "hithere");
+        assert_eq!((vm.registers[2], vm.registers[3]), (17, 7));
+        assert_eq!(&vm.heap[17..], b"hithere\0");
+        assert!(vm.eq_flag);
+        vm.run_back_until(|vm| vm.pc == 4);
+        assert_eq!((vm.output(), vm.heap.len()), ("hi", 17));
+        assert_eq!(vm.take_output(), "hi");
+        assert_eq!(vm.output(), "");
+        // restoring drops the output, which isn't part of a snapshot
+        let snapshot = vm.snapshot();
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.output(), "there");
+        vm.restore(&snapshot);
+        assert_eq!((vm.pc, vm.output()), (4, ""));
+
+        let run = |address: i32| {
+            let mut vm = new_test_vm();
+            vm.set_heap(heap.clone());
+            vm.registers[0] = address;
+            vm.program = vec![75, 0, 1, 0];
+            vm.run()
+        };
+        let fault = |fault| Outcome::Fault(fault);
+        assert_eq!(run(9), fault(Fault::InvalidUtf8(9)));
+        assert_eq!(run(11), fault(Fault::UnterminatedString(11)));
+        assert_eq!(run(-1), fault(Fault::InvalidAddress(-1)));
+        assert_eq!(run(17), fault(Fault::InvalidAddress(17)));
+        assert_eq!(run(8), Outcome::Finished);
+    }
+
+    #[test]
+    fn test_floats() {
+        let mut vm = new_test_vm();
+        vm.set_constants(vec![1.5, -4.0]);
+        vm.program = vec![
+            57, 0, 0, 0, // load constant 0 to $f0
+            57, 1, 0, 1, // load constant 1 to $f1
+            60, 0, 1, 2, // $f2 = $f0 * $f1
+            1, 0, 0, 3, // load 3 to $0
+            68, 0, 3, 0, // $f3 = $0
+            61, 2, 3, 4, // $f4 = $f2 / $f3
+            69, 4, 1, 0, // $1 = $f4
+            65, 4, 1, 0, // $f4 < $f1
+        ];
+        vm.enable_history(8);
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.float_registers[..5], [1.5, -4.0, -6.0, 3.0, -2.0]);
+        assert_eq!(vm.registers[1], -2);
+        assert!(!vm.eq_flag);
+        // floats leave the flags alone, and only FTOI moves stdout
+        assert_eq!((vm.flags, vm.stdout), (Flags::default(), 1));
+        vm.run_back_until(|vm| vm.pc == 8);
+        assert_eq!(vm.float_registers[2..5], [0.0; 3]);
+        assert_eq!(vm.registers[..2], [0, 0]);
+
+        // dividing by zero is fine, and converting saturates
+        let convert = |v: f64| {
+            let mut vm = new_test_vm();
+            vm.float_registers[0] = v;
+            vm.program = vec![69, 0, 0, 0];
+            vm.run();
+            vm.registers[0]
+        };
+        assert_eq!(convert(-2.9), -2);
+        assert_eq!(convert(1e10), i32::MAX);
+        assert_eq!(convert(f64::NAN), 0);
+        let mut vm = new_test_vm();
+        vm.program = vec![61, 0, 1, 2, 63, 2, 2, 0];
+        vm.run();
+        assert!(vm.float_registers[2].is_nan());
+        assert!(vm.eq_flag);
+
+        let mut vm = new_test_vm();
+        vm.program = vec![57, 0, 0, 1];
+        assert_eq!(vm.run(), Outcome::Fault(Fault::InvalidConstant(1)));
+        let mut vm = new_test_vm();
+        vm.program = vec![58, 0, 0, 32];
+        assert_eq!(vm.run(), Outcome::Fault(Fault::InvalidRegister(32)));
+    }
+
+    #[test]
+    fn test_run_outcomes() {
+        let mut vm = new_test_vm();
+        vm.program = vec![1, 0, 0, 1];
+        assert_eq!(vm.run(), Outcome::Finished);
+        vm.program.extend([0, 0, 0, 0]);
+        assert_eq!(vm.run(), Outcome::Halted);
+        let mut vm = new_test_vm();
+        vm.program = vec![200, 0, 0, 0];
+        assert_eq!(vm.run(), Outcome::Fault(Fault::IllegalOpcode(200)));
+    }
+
+    #[test]
+    fn test_budget_stops_infinite_loop() {
+        let mut vm = new_test_vm();
+        vm.program = vec![6, 0, 0, 0]; // jump to r0, which is 0
+        assert_eq!(vm.run_with_budget(100), Outcome::OutOfFuel);
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.pc, 0);
+    }
+
+    #[test]
+    fn test_out_of_fuel_is_resumable() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 1, // load 1 to register 0
+            2, 0, 0, 0, // double r0
+            2, 0, 0, 0, // double r0
+            0, 0, 0, 0, // halt
+        ];
+        assert_eq!(vm.run_with_budget(2), Outcome::OutOfFuel);
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.pc, 8);
+        assert_eq!(vm.run_with_budget(2), Outcome::Halted);
+        assert_eq!(vm.registers[0], 4);
+        assert_eq!(vm.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_per_opcode_costs() {
+        let mut vm = new_test_vm();
+        vm.set_cost(Opcode::MUL, 10);
+        vm.set_cost(Opcode::LOAD, 0);
+        assert_eq!(vm.cost(Opcode::ADD), 1);
+        vm.program = vec![
+            1, 0, 0, 3, // load 3 to register 0
+            4, 0, 0, 1, // load r0 * r0 to r1
+            2, 0, 1, 2, // load r0 + r1 to r2
+        ];
+        assert_eq!(vm.run_with_budget(5), Outcome::OutOfFuel);
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.fuel(), Some(5));
+        vm.set_fuel(Some(11));
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[2], 12);
+        assert_eq!(vm.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_interrupt_from_another_thread() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 4;
+        vm.program = vec![
+            1, 1, 0, 1, // load 1 to register 1
+            6, 0, 0, 0, // jump back to the jump itself, forever
+        ];
+        let handle = vm.interrupt_handle();
+        let running = std::thread::spawn(move || {
+            let outcome = vm.run();
+            (outcome, vm)
+        });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        handle.interrupt();
+        let (outcome, vm) = running.join().unwrap();
+        assert_eq!(outcome, Outcome::Interrupted);
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.registers[1], 1);
+        assert!(!handle.is_pending());
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 1, // load 1 to register 0
+            2, 0, 0, 0, // double r0
+            5, 0, 0, 1, // load r0 / r0 to r1
+            2, 0, 0, 0, // double r0
+        ];
+        vm.run_with_budget(2);
+        let snapshot = Snapshot::from_bytes(&vm.snapshot().to_bytes()).unwrap();
+        vm.set_fuel(None);
+        vm.run();
+        assert_eq!(vm.registers[0], 4);
+
+        let mut restored = new_test_vm();
+        restored.restore(&snapshot);
+        assert_eq!(restored.pc, 8);
+        assert_eq!(restored.registers[0], 2);
+        assert_eq!(restored.run(), Outcome::Finished);
+        assert_eq!(restored.snapshot(), vm.snapshot());
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 5, // load 5 to register 0
+            1, 1, 0, 5, // load 5 to register 1
+            9, 0, 1, 0, // compare r0 and r1 for equality
+            2, 0, 1, 2, // load r0 + r1 to r2
+            9, 0, 1, 0, // compare again, the flag does not change
+        ];
+        vm.run_once();
+        vm.start_trace();
+        vm.run();
+        let trace = vm.stop_trace().unwrap();
+        assert!(vm.trace().is_none());
+
+        let entries = trace.entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].pc, 4);
+        assert_eq!(entries[0].writes, vec![(1, 5)]);
+        assert_eq!(entries[1].instruction.opcode(), Opcode::EQ);
+        assert_eq!(entries[1].eq_flag, Some((false, true)));
+        assert_eq!(entries[2].writes, vec![(2, 10)]);
+        assert_eq!(entries[3].eq_flag, None);
+        let zero = Flags::from_bits(1).unwrap();
+        assert_eq!(entries[0].flags, None);
+        assert_eq!(entries[1].flags, Some((Flags::default(), zero)));
+        assert_eq!(entries[2].flags, Some((zero, Flags::default())));
+        assert_eq!(
+            trace.to_json_lines().lines().collect::<Vec<_>>(),
+            vec![
+                r#"{"pc":4,"op":"load","bytes":[1,1,0,5],"asm":"load $1 #5","writes":[{"reg":1,"value":5}]}"#,
+                r#"{"pc":8,"op":"eq","bytes":[9,0,1,0],"asm":"eq $0 $1","writes":[],"eq_flag":{"from":false,"to":true},"flags":{"from":"----","to":"Z---"}}"#,
+                r#"{"pc":12,"op":"add","bytes":[2,0,1,2],"asm":"add $0 $1 $2","writes":[{"reg":2,"value":10}],"flags":{"from":"Z---","to":"----"}}"#,
+                r#"{"pc":16,"op":"eq","bytes":[9,0,1,0],"asm":"eq $0 $1","writes":[],"flags":{"from":"----","to":"Z---"}}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_floats() {
+        let mut vm = new_test_vm();
+        vm.set_constants(vec![2.5]);
+        vm.program = vec![
+            57, 1, 0, 0, // load constant 0 to $f1
+            61, 0, 2, 3, // $f3 = $f0 / $f2, which is NaN
+        ];
+        vm.start_trace();
+        vm.run();
+        let trace = vm.stop_trace().unwrap();
+
+        let entries = trace.entries();
+        assert_eq!(entries[0].float_writes, vec![(1, 2.5)]);
+        assert!(entries[0].writes.is_empty());
+        assert_eq!(
+            trace.to_json_lines().lines().collect::<Vec<_>>(),
+            vec![
+                r#"{"pc":0,"op":"loadf","bytes":[57,1,0,0],"asm":"loadf $f1 #0","writes":[],"float_writes":[{"reg":1,"value":2.5}]}"#,
+                r#"{"pc":4,"op":"divf","bytes":[61,0,2,3],"asm":"divf $f0 $f2 $f3","writes":[],"float_writes":[{"reg":3,"value":null}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut vm = new_test_vm();
+        vm.enable_history(16);
+        vm.program = vec![
+            1, 0, 0, 3, // load 3 to register 0
+            1, 1, 0, 4, // load 4 to register 1
+            9, 0, 1, 0, // compare r0 and r1 for equality
+            5, 1, 0, 0, // load r1 / r0 to r0
+            16, 1, 0, 0, // allocate r1 bytes
+        ];
+        vm.run();
+        assert_eq!(vm.history_len(), 5);
+        assert_eq!((vm.registers[0], vm.remainder, vm.heap.len()), (1, 1, 4));
+
+        assert!(vm.step_back());
+        assert_eq!(vm.heap.len(), 0);
+        assert!(vm.step_back());
+        assert_eq!((vm.pc, vm.registers[0], vm.remainder), (12, 3, 0));
+        assert_eq!(vm.stdout, 1);
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!((vm.pc, vm.registers[0], vm.registers[1]), (0, 0, 0));
+        assert!(!vm.step_back());
+
+        // replaying gets us back to the same place
+        vm.run();
+        assert_eq!((vm.registers[0], vm.remainder, vm.heap.len()), (1, 1, 4));
+    }
+
+    #[test]
+    fn test_step_back_over_shrinking_alloc() {
+        let mut vm = new_test_vm();
+        vm.enable_history(4);
+        vm.registers[0] = 4;
+        vm.registers[1] = -3;
+        vm.program = vec![
+            16, 0, 0, 0, // allocate 4 bytes
+            16, 1, 0, 0, // free 3 of them
+        ];
+        vm.run_once();
+        vm.heap.copy_from_slice(&[1, 2, 3, 4]);
+        vm.run_once();
+        assert_eq!(vm.heap, [1]);
+        assert!(vm.step_back());
+        assert_eq!(vm.heap, [1, 2, 3, 4]);
+        assert!(vm.step_back());
+        assert_eq!(vm.heap, []);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut vm = new_test_vm();
+        vm.enable_history(2);
+        vm.registers[1] = 1;
+        vm.program = vec![
+            2, 0, 1, 0, // load r0 + r1 to r0
+            2, 0, 1, 0, // load r0 + r1 to r0
+            2, 0, 1, 0, // load r0 + r1 to r0
+        ];
+        vm.run();
+        assert_eq!(vm.history_len(), 2);
+        while vm.step_back() {}
+        assert_eq!((vm.pc, vm.registers[0]), (4, 1));
+    }
+
+    #[test]
+    fn test_run_back_until() {
+        let mut vm = new_test_vm();
+        vm.enable_history(100);
+        vm.program = vec![
+            1, 0, 0, 1, // load 1 to register 0
+            1, 2, 0, 8, // load 8 to register 2
+            2, 1, 0, 1, // load r1 + r0 to r1
+            6, 2, 0, 0, // jump to 8
+        ];
+        vm.run_with_budget(50);
+        assert!(vm.run_back_until(|vm| vm.pc == 8 && vm.registers[1] == 5));
+        assert_eq!(vm.registers[1], 5);
+        assert!(!vm.run_back_until(|vm| vm.pc == 100));
+        assert_eq!((vm.pc, vm.registers[1]), (0, 0));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 1, // load 1 to register 0
+            1, 2, 0, 8, // load 8 to register 2
+            2, 1, 0, 1, // load r1 + r0 to r1
+            6, 2, 0, 0, // jump to 8
+        ];
+        vm.add_breakpoint(8);
+        assert_eq!(vm.run(), Outcome::Breakpoint(BreakpointHit::Address(8)));
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.run(), Outcome::Breakpoint(BreakpointHit::Address(8)));
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.registers[1], 2);
+        assert!(vm.remove_breakpoint(8));
+        assert!(!vm.remove_breakpoint(8));
+        assert_eq!(vm.run_with_budget(10), Outcome::OutOfFuel);
+    }
+
+    #[test]
+    fn test_breakpoint_after_out_of_fuel() {
+        let mut vm = new_test_vm();
+        vm.program = vec![1, 0, 0, 1, 1, 1, 0, 1];
+        vm.add_breakpoint(4);
+        assert_eq!(vm.run_with_budget(0), Outcome::OutOfFuel);
+        assert_eq!(
+            vm.run_with_budget(5),
+            Outcome::Breakpoint(BreakpointHit::Address(4))
+        );
+        assert_eq!(vm.run_with_budget(0), Outcome::OutOfFuel);
+        assert_eq!(vm.run_with_budget(5), Outcome::Finished);
+    }
+
+    #[test]
+    fn test_register_watchpoints() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 1, // load 1 to register 0
+            1, 1, 0, 1, // load 1 to register 1
+            2, 1, 0, 1, // load r1 + r0 to r1
+            1, 1, 0, 2, // load 2 to register 1
+            2, 1, 0, 1, // load r1 + r0 to r1
+        ];
+        vm.add_watchpoint(Watchpoint::RegisterValue(1, 3));
+        vm.add_watchpoint(Watchpoint::Register(0));
+        let write = |pc, register, old, new| {
+            Outcome::Breakpoint(BreakpointHit::Register {
+                pc,
+                register,
+                old,
+                new,
+            })
+        };
+        assert_eq!(vm.run(), write(0, 0, 0, 1));
+        assert!(vm.remove_watchpoint(Watchpoint::Register(0)));
+        assert_eq!(vm.run(), write(16, 1, 2, 3));
+        assert_eq!(vm.run(), Outcome::Finished);
+    }
+
+    #[test]
+    fn test_heap_and_flag_watchpoints() {
+        let mut vm = new_test_vm();
+        vm.registers[0] = 2;
+        vm.registers[1] = 3;
+        vm.program = vec![
+            9, 0, 1, 0, // compare r0 and r1 for equality
+            16, 0, 0, 0, // allocate r0 bytes
+            16, 1, 0, 0, // allocate r1 bytes
+            10, 0, 1, 0, // compare r0 and r1 for inequality
+        ];
+        vm.add_watchpoint(Watchpoint::Heap(3));
+        vm.add_watchpoint(Watchpoint::EqFlag);
+        assert_eq!(
+            vm.run(),
+            Outcome::Breakpoint(BreakpointHit::Heap {
+                pc: 8,
+                address: 3,
+                old: None,
+                new: Some(0)
+            })
+        );
+        assert_eq!(
+            vm.run(),
+            Outcome::Breakpoint(BreakpointHit::EqFlag {
+                pc: 12,
+                old: false,
+                new: true
+            })
+        );
+        assert_eq!(vm.run(), Outcome::Finished);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut vm = new_test_vm();
+        vm.enable_history(100);
+        vm.program = vec![
+            1, 0, 0, 1, // load 1 to register 0
+            1, 2, 0, 8, // load 8 to register 2
+            2, 1, 0, 1, // load r1 + r0 to r1
+            6, 2, 0, 0, // jump to 8
+        ];
+        vm.add_breakpoint(12);
+        vm.run();
+        vm.run();
+        vm.run();
+        assert_eq!(vm.registers[1], 3);
+        assert_eq!(vm.reverse_continue(), Some(BreakpointHit::Address(12)));
+        assert_eq!(vm.registers[1], 2);
+        assert_eq!(vm.reverse_continue(), Some(BreakpointHit::Address(12)));
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.reverse_continue(), None);
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.run(), Outcome::Breakpoint(BreakpointHit::Address(12)));
+        assert_eq!(vm.registers[1], 1);
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        before: Vec<(usize, Opcode)>,
+        after: Vec<(usize, i32)>,
+        faults: Vec<(usize, Fault)>,
+    }
+
+    impl ExecutionObserver for Recorder {
+        fn before_instruction(&mut self, _vm: &VM, pc: usize, instruction: Instruction) {
+            self.before.push((pc, instruction.opcode()));
+        }
+
+        fn after_instruction(&mut self, vm: &VM, pc: usize, _instruction: Instruction) {
+            self.after.push((pc, vm.stdout()));
+        }
+
+        fn on_fault(&mut self, _vm: &VM, pc: usize, fault: Fault) {
+            self.faults.push((pc, fault));
+        }
+    }
+
+    struct Counter(usize);
+
+    impl ExecutionObserver for Counter {
+        fn after_instruction(&mut self, _vm: &VM, _pc: usize, _instruction: Instruction) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let mut vm = new_test_vm();
+        vm.program = vec![
+            1, 0, 0, 6, // load 6 to register 0
+            1, 1, 0, 3, // load 3 to register 1
+            5, 0, 1, 2, // load r0 / r1 to r2
+            5, 0, 3, 2, // load r0 / r3 to r2
+        ];
+        vm.attach_observer(Recorder::default());
+        vm.attach_observer(Counter(0));
+        assert_eq!(vm.run(), Outcome::Fault(Fault::DivisionByZero));
+
+        assert_eq!(vm.observer::<Counter>().unwrap().0, 3);
+        vm.observer_mut::<Counter>().unwrap().0 = 10;
+        let recorder = vm.detach_observer::<Recorder>().unwrap();
+        assert!(vm.observer::<Recorder>().is_none());
+        assert_eq!(
+            recorder.before,
+            vec![(0, Opcode::LOAD), (4, Opcode::LOAD), (8, Opcode::DIV), (12, Opcode::DIV)]
+        );
+        assert_eq!(recorder.after, vec![(0, 6), (4, 3), (8, 2)]);
+        assert_eq!(recorder.faults, vec![(12, Fault::DivisionByZero)]);
+        assert_eq!(vm.detach_observer::<Counter>().unwrap().0, 10);
+    }
+
+    #[test]
+    fn test_faults() {
+        let run = |program: Vec<u8>| {
+            let mut vm = new_test_vm();
+            vm.registers[1] = -8;
+            vm.registers[2] = 8;
+            vm.program = program;
+            vm.run()
+        };
+        let fault = Outcome::Fault;
+        assert_eq!(run(vec![1, 32, 0, 1]), fault(Fault::InvalidRegister(32)));
+        assert_eq!(run(vec![2, 0, 1, 200]), fault(Fault::InvalidRegister(200)));
+        assert_eq!(run(vec![1, 0, 0]), fault(Fault::TruncatedInstruction));
+        assert_eq!(run(vec![8, 2, 0, 0]), fault(Fault::InvalidJump(-6)));
+        assert_eq!(run(vec![6, 1, 0, 0]), fault(Fault::InvalidJump(-8)));
+        assert_eq!(run(vec![16, 1, 0, 0]), fault(Fault::InvalidAllocation(-8)));
+    }
+
+    #[test]
+    fn test_predecoding() {
+        let program = [
+            1, 0, 0, 9, // load $0 #9
+            1, 1, 0, 2, // load $1 #2
+            5, 0, 1, 2, // div $0 $1 $2
+            9, 0, 2, 0, // eq $0 $2
+            7, 3, 0, 0, // jmpf $3, lands mid-instruction on the hlt padding
+        ];
+        let run = |predecoding: bool| {
+            let mut vm = new_test_vm();
+            vm.set_predecoding(predecoding);
+            vm.stdin(program);
+            (vm.run(), vm.pc, vm.registers, vm.remainder, vm.eq_flag)
+        };
+        let decoded = run(true);
+        assert_eq!(decoded, run(false));
+        assert_eq!((decoded.0, decoded.1), (Outcome::Halted, 19));
+        assert_eq!(decoded.3, 1);
+    }
+
+    #[test]
+    fn test_predecoded_program_grows() {
+        let mut vm = new_test_vm();
+        vm.stdin([1, 0, 0, 5]);
+        assert_eq!(vm.run(), Outcome::Finished);
+        vm.stdin([2, 0, 0, 1]);
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[1], 10);
+
+        let snapshot = vm.snapshot();
+        vm.program = vec![1, 0, 0, 7];
+        vm.pc = 0;
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[0], 7);
+        vm.restore(&snapshot);
+        vm.pc = 4;
+        assert_eq!(vm.run(), Outcome::Finished);
+        assert_eq!(vm.registers[1], 10);
+    }
+
+    #[test]
+    fn test_pending_interrupt() {
+        let mut vm = new_test_vm();
+        vm.program = vec![1, 0, 0, 1];
+        let handle = vm.interrupt_handle();
+        handle.interrupt();
+        assert_eq!(vm.run(), Outcome::Interrupted);
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.run(), Outcome::Finished);
+        handle.interrupt();
+        handle.clear();
+        assert_eq!(vm.run_once(), Some(Outcome::Finished));
+    }
+}
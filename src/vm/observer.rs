@@ -0,0 +1,17 @@
+use super::{Fault, VM};
+use crate::instructions::Instruction;
+use std::any::Any;
+
+/// Hooks into instruction execution, for tracers, profilers, coverage tools
+/// and debuggers. Every callback does nothing unless overridden. Observers
+/// must be `Send` so a VM can still be moved to another thread.
+pub trait ExecutionObserver: Any + Send {
+    /// Called right before the instruction at `pc` runs.
+    fn before_instruction(&mut self, _vm: &VM, _pc: usize, _instruction: Instruction) {}
+
+    /// Called once the instruction at `pc` has run.
+    fn after_instruction(&mut self, _vm: &VM, _pc: usize, _instruction: Instruction) {}
+
+    /// Called instead of `after_instruction` when the instruction at `pc` faulted.
+    fn on_fault(&mut self, _vm: &VM, _pc: usize, _fault: Fault) {}
+}
@@ -0,0 +1,309 @@
+use super::Flags;
+use std::fmt;
+
+// On-disk layout, all integers little endian:
+//
+//   magic       4 bytes   "IRDM"
+//   version     u16
+//   registers   32 x i32
+//   remainder   i32
+//   eq_flag     u8
+//   flags       u8, as `Flags::bits`, since version 2
+//   stdout      u32
+//   pc          u64
+//   heap        u64 length, then the bytes
+//   program     u64 length, then the bytes
+//   float registers  32 x f64, since version 3
+//   constants   u64 length, then the f64s, since version 3
+//
+// Any change to this layout must bump VERSION. Older versions still decode,
+// with whatever they lack left at its default: flags clear, float registers
+// 0.0 and no constants.
+const MAGIC: &[u8; 4] = b"IRDM";
+const VERSION: u16 = 3;
+
+/// Everything needed to put a VM back in the exact state it was in.
+/// Host settings such as fuel, opcode costs or interrupt handles are not part of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub registers: [i32; 32],
+    pub remainder: i32,
+    pub eq_flag: bool,
+    pub flags: Flags,
+    pub stdout: usize,
+    pub heap: Vec<u8>,
+    pub pc: usize,
+    pub program: Vec<u8>,
+    pub float_registers: [f64; 32],
+    pub constants: Vec<f64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not an iridium snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {v}"),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(reason) => write!(f, "invalid snapshot: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, SnapshotError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(u64::from_le_bytes(self.array()?))
+            .map_err(|_| SnapshotError::Invalid("length does not fit in memory"))
+    }
+
+    fn f64(&mut self) -> Result<f64, SnapshotError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn floats(&mut self) -> Result<Vec<f64>, SnapshotError> {
+        let len = self.usize()?;
+        let bytes = self.take(len.checked_mul(8).ok_or(SnapshotError::Truncated)?)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect())
+    }
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            435 + self.heap.len() + self.program.len() + self.constants.len() * 8,
+        );
+        out.extend(MAGIC);
+        out.extend(VERSION.to_le_bytes());
+        self.registers
+            .iter()
+            .for_each(|register| out.extend(register.to_le_bytes()));
+        out.extend(self.remainder.to_le_bytes());
+        out.push(self.eq_flag as u8);
+        out.push(self.flags.bits());
+        out.extend((self.stdout as u32).to_le_bytes());
+        out.extend((self.pc as u64).to_le_bytes());
+        out.extend((self.heap.len() as u64).to_le_bytes());
+        out.extend(&self.heap);
+        out.extend((self.program.len() as u64).to_le_bytes());
+        out.extend(&self.program);
+        self.float_registers
+            .iter()
+            .for_each(|register| out.extend(register.to_le_bytes()));
+        out.extend((self.constants.len() as u64).to_le_bytes());
+        self.constants
+            .iter()
+            .for_each(|constant| out.extend(constant.to_le_bytes()));
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut r = Reader(bytes);
+        if r.take(4).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = match r.u16()? {
+            version @ 1..=VERSION => version,
+            version => return Err(SnapshotError::UnsupportedVersion(version)),
+        };
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = r.i32()?;
+        }
+        let remainder = r.i32()?;
+        let eq_flag = match r.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Invalid("eq_flag is not a boolean")),
+        };
+        let flags = match version {
+            1 => Flags::default(),
+            _ => Flags::from_bits(r.u8()?)
+                .ok_or(SnapshotError::Invalid("unknown bits in the flags"))?,
+        };
+        let stdout = r.u32()? as usize;
+        if stdout >= registers.len() && stdout != 33 {
+            return Err(SnapshotError::Invalid("stdout does not name a register"));
+        }
+        let pc = r.usize()?;
+        let heap = r.bytes()?;
+        let program = r.bytes()?;
+        let mut float_registers = [0.0; 32];
+        let mut constants = vec![];
+        if version >= 3 {
+            for register in float_registers.iter_mut() {
+                *register = r.f64()?;
+            }
+            constants = r.floats()?;
+        }
+        if !r.0.is_empty() {
+            return Err(SnapshotError::Invalid("trailing bytes"));
+        }
+        Ok(Snapshot {
+            registers,
+            remainder,
+            eq_flag,
+            flags,
+            stdout,
+            heap,
+            pc,
+            program,
+            float_registers,
+            constants,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Snapshot {
+        let mut registers = [0; 32];
+        registers[0] = -2;
+        registers[31] = 0x01020304;
+        let mut float_registers = [0.0; 32];
+        float_registers[1] = -0.5;
+        Snapshot {
+            registers,
+            remainder: 7,
+            eq_flag: true,
+            flags: Flags {
+                carry: true,
+                ..Flags::default()
+            },
+            stdout: 31,
+            heap: vec![0xAA, 0xBB],
+            pc: 4,
+            program: vec![1, 0, 0, 9],
+            float_registers,
+            constants: vec![2.0],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = sample();
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+    }
+
+    #[test]
+    fn test_encoding_is_stable() {
+        let bytes = sample().to_bytes();
+        assert_eq!(&bytes[..6], b"IRDM\x03\x00");
+        assert_eq!(&bytes[6..10], &[0xFE, 0xFF, 0xFF, 0xFF]); // r0
+        assert_eq!(&bytes[130..134], &[4, 3, 2, 1]); // r31
+        assert_eq!(&bytes[134..144], &[7, 0, 0, 0, 1, 4, 31, 0, 0, 0]); // remainder, flags, stdout
+        assert_eq!(&bytes[144..152], &[4, 0, 0, 0, 0, 0, 0, 0]); // pc
+        assert_eq!(&bytes[152..162], &[2, 0, 0, 0, 0, 0, 0, 0, 0xAA, 0xBB]); // heap
+        assert_eq!(&bytes[162..174], &[4, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 9]); // program
+        assert_eq!(&bytes[182..190], &(-0.5f64).to_le_bytes()); // $f1
+        assert_eq!(&bytes[430..438], &[1, 0, 0, 0, 0, 0, 0, 0]); // constants
+        assert_eq!(&bytes[438..], &2.0f64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_decodes_older_versions() {
+        let bytes = sample().to_bytes();
+        // version 1 had no flags byte, and neither had float registers or constants
+        let v1 = [b"IRDM\x01\x00", &bytes[6..139], &bytes[140..174]].concat();
+        let v2 = [b"IRDM\x02\x00", &bytes[6..174]].concat();
+        let snapshot = Snapshot::from_bytes(&v1).unwrap();
+        assert_eq!(
+            snapshot,
+            Snapshot {
+                flags: Flags::default(),
+                float_registers: [0.0; 32],
+                constants: vec![],
+                ..sample()
+            }
+        );
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+        assert_eq!(
+            Snapshot::from_bytes(&v2),
+            Ok(Snapshot {
+                float_registers: [0.0; 32],
+                constants: vec![],
+                ..sample()
+            })
+        );
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert_eq!(Snapshot::from_bytes(b"nope"), Err(SnapshotError::BadMagic));
+        assert_eq!(Snapshot::from_bytes(b"IR"), Err(SnapshotError::BadMagic));
+        assert_eq!(
+            Snapshot::from_bytes(b"IRDM\x09\x00"),
+            Err(SnapshotError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            Snapshot::from_bytes(b"IRDM\x00\x00"),
+            Err(SnapshotError::UnsupportedVersion(0))
+        );
+        let bytes = sample().to_bytes();
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        let mut bytes = sample().to_bytes();
+        bytes[138] = 2;
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::Invalid("eq_flag is not a boolean"))
+        );
+        let mut bytes = sample().to_bytes();
+        bytes[139] = 0x10;
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::Invalid("unknown bits in the flags"))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use super::Flags;
+use crate::instructions::Instruction;
+use std::fmt::Write as _;
+use std::io;
+
+/// What a single instruction did, as seen by the tracer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    /// address the instruction was fetched from
+    pub pc: usize,
+    pub instruction: Instruction,
+    /// every register write as (register, value), in order
+    pub writes: Vec<(usize, i32)>,
+    /// every float register write as (register, value), in order
+    pub float_writes: Vec<(usize, f64)>,
+    /// (before, after), only when the instruction changed the flag
+    pub eq_flag: Option<(bool, bool)>,
+    /// (before, after), only when the instruction changed the condition flags
+    pub flags: Option<(Flags, Flags)>,
+}
+
+/// Per-instruction log of an execution, in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace { entries: vec![] }
+    }
+
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.entries
+    }
+
+    pub(super) fn begin(&mut self, pc: usize, instruction: Instruction) {
+        self.entries.push(TraceEntry {
+            pc,
+            instruction,
+            writes: vec![],
+            float_writes: vec![],
+            eq_flag: None,
+            flags: None,
+        });
+    }
+
+    pub(super) fn record_write(&mut self, register: usize, value: i32) {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.writes.push((register, value));
+        }
+    }
+
+    pub(super) fn record_float_write(&mut self, register: usize, value: f64) {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.float_writes.push((register, value));
+        }
+    }
+
+    pub(super) fn record_eq_flag(&mut self, before: bool, after: bool) {
+        if let Some(entry) = self.entries.last_mut() {
+            if before != after {
+                entry.eq_flag = Some((before, after));
+            }
+        }
+    }
+
+    pub(super) fn record_flags(&mut self, before: Flags, after: Flags) {
+        if let Some(entry) = self.entries.last_mut() {
+            if before != after {
+                entry.flags = Some((before, after));
+            }
+        }
+    }
+
+    /// Writes one JSON object per executed instruction, one per line.
+    pub fn write_json_lines(&self, mut out: impl io::Write) -> io::Result<()> {
+        self.entries
+            .iter()
+            .try_for_each(|entry| writeln!(out, "{}", entry.to_json()))
+    }
+
+    pub fn to_json_lines(&self) -> String {
+        let mut out = vec![];
+        self.write_json_lines(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+impl TraceEntry {
+    // the disassembly only ever contains mnemonics, registers and numbers,
+    // so nothing in it needs escaping. float_writes is left out when empty,
+    // and a NaN or infinite value is written as null, which JSON has no
+    // numbers for
+    fn to_json(&self) -> String {
+        let mut json = String::new();
+        let op = self.instruction.opcode();
+        let [a, b, c] = self.instruction.operands();
+        write!(
+            json,
+            r#"{{"pc":{},"op":"{}","bytes":[{},{},{},{}],"asm":"{}","writes":["#,
+            self.pc, op, op as u8, a, b, c, self.instruction
+        )
+        .unwrap();
+        for (i, (register, value)) in self.writes.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(json, r#"{separator}{{"reg":{register},"value":{value}}}"#).unwrap();
+        }
+        json.push(']');
+        if !self.float_writes.is_empty() {
+            json.push_str(r#","float_writes":["#);
+            for (i, (register, value)) in self.float_writes.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                let value = match value.is_finite() {
+                    true => value.to_string(),
+                    false => "null".to_string(),
+                };
+                write!(json, r#"{separator}{{"reg":{register},"value":{value}}}"#).unwrap();
+            }
+            json.push(']');
+        }
+        if let Some((before, after)) = self.eq_flag {
+            write!(json, r#","eq_flag":{{"from":{before},"to":{after}}}"#).unwrap();
+        }
+        if let Some((before, after)) = self.flags {
+            write!(json, r#","flags":{{"from":"{before}","to":"{after}"}}"#).unwrap();
+        }
+        json.push('}');
+        json
+    }
+}